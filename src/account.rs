@@ -1,18 +1,78 @@
+use crate::crypto;
+use anyhow::Context;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
 use rand::distr::{Alphanumeric, SampleString};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Account {
     pub id: u32,
     pub protocol: String,
     pub user: String,
+    #[serde(default)]
+    pub homeserver: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
+    pub password_command: String,
+    #[serde(default)]
+    pub forget_password: bool,
+    #[serde(default)]
+    pub access_token: String,
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub login_token: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Users and servers whose invites are accepted automatically.
+    #[serde(default)]
+    pub auto_accept_invites: Vec<String>,
+    /// Decline invites from users who do not share a chat with the account.
+    #[serde(default)]
+    pub reject_unknown_invites: bool,
+    /// Status of the account set by the user, kept across restarts.
+    #[serde(default = "default_status")]
+    pub status: String,
+    /// Status message of the account set by the user.
+    #[serde(default)]
+    pub status_message: String,
+    /// Registration token if the account should be registered, empty if not needed.
+    #[serde(skip)]
+    pub registration: Option<String>,
     pub db_passphrase: String,
+    #[serde(default)]
     pub secret_store_key: String,
+    /// Name of the data directory of the account in the "data" directory.
+    #[serde(default)]
+    pub data_dir: String,
+}
+
+/// Accounts are enabled by default, also in accounts files without the enabled flag.
+fn default_enabled() -> bool {
+    true
+}
+
+/// Accounts are online by default, also in accounts files without the status.
+fn default_status() -> String {
+    "online".into()
+}
+
+/// Creates a new random name for the data directory of an account.
+fn new_data_dir() -> String {
+    Alphanumeric.sample_string(&mut rand::rng(), 16)
+}
+
+/// Checks if `name` is a single normal path component, i.e., it cannot escape its parent dir.
+fn is_normal_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
 }
 
 impl Account {
@@ -21,14 +81,50 @@ impl Account {
             id,
             protocol,
             user,
+            homeserver: String::new(),
             password,
+            password_command: String::new(),
+            forget_password: false,
+            access_token: String::new(),
+            device_id: String::new(),
+            login_token: String::new(),
+            enabled: true,
+            auto_accept_invites: Vec::new(),
+            reject_unknown_invites: false,
+            status: default_status(),
+            status_message: String::new(),
+            registration: None,
             db_passphrase: Alphanumeric.sample_string(&mut rand::rng(), 16),
             secret_store_key: String::new(),
+            data_dir: new_data_dir(),
         }
     }
 
-    /// Splits the user into user name and server.
+    /// Gets the data directory of the account in the working directory `dir`.
+    pub fn data_path(&self, dir: &Path) -> PathBuf {
+        dir.join("data").join(&self.data_dir)
+    }
+
+    /// Gets the data directory of the account in the working directory `dir` used before
+    /// accounts file version 2, if its path cannot escape the "data" directory.
+    fn legacy_data_path(&self, dir: &Path) -> Option<PathBuf> {
+        let (user, server) = self.split_user();
+        if !is_normal_component(&server) || !is_normal_component(&user) {
+            return None;
+        }
+        Some(dir.join("data").join(server).join(user))
+    }
+
+    /// Splits the user into user name and server. The user is either a full Matrix user ID
+    /// like "@alice:example.org" or "alice@example.org".
     pub fn split_user(&self) -> (String, String) {
+        if let Some(user_id) = self.user.strip_prefix("@")
+            && let Some((user, server)) = user_id.split_once(":")
+            && !user.is_empty()
+            && !server.is_empty()
+        {
+            return (user.into(), server.into());
+        }
         match self.user.split_once("@") {
             Some(("", server)) => (self.user.clone(), server.into()),
             Some((user, "")) => (user.into(), self.user.clone()),
@@ -37,6 +133,17 @@ impl Account {
         }
     }
 
+    /// Gets the Matrix user ID of the account and checks its format.
+    pub fn user_id(&self) -> anyhow::Result<OwnedUserId> {
+        if !self.user.contains("@") {
+            anyhow::bail!("invalid user {}, expected <name>@<server>", self.user);
+        }
+        let (user, server) = self.split_user();
+        let user_id = UserId::parse(format!("@{user}:{server}"))
+            .map_err(|err| anyhow::anyhow!("invalid user {}: {err}", self.user))?;
+        Ok(user_id)
+    }
+
     /// Gets the name of the account.
     pub fn get_name(&self) -> String {
         let (_, server) = self.split_user();
@@ -44,17 +151,104 @@ impl Account {
     }
 }
 
+/// Current version of the accounts file format.
+const ACCOUNTS_FILE_VERSION: u32 = 2;
+
+/// Accounts file with version of the file format.
+#[derive(Deserialize, Serialize)]
+struct AccountsFile {
+    version: u32,
+    accounts: Vec<Account>,
+}
+
+/// Migrates the content `j` of the accounts file from its version to the current version.
+/// Returns the version of the file before the migration.
+fn migrate_accounts_file(j: &mut serde_json::Value) -> anyhow::Result<u32> {
+    // version 0 is a plain list of accounts
+    let version = if j.is_array() {
+        0
+    } else {
+        let version = j["version"]
+            .as_u64()
+            .context("Accounts file has no version")?;
+        u32::try_from(version)?
+    };
+    if version > ACCOUNTS_FILE_VERSION {
+        anyhow::bail!("Unsupported accounts file version {version}");
+    }
+
+    // migrate step by step to the current version
+    if version < 1 {
+        *j = serde_json::json!({ "version": 1, "accounts": j });
+    }
+    if version < 2 {
+        // data directories are not named after user and server any more
+        let accounts = j["accounts"]
+            .as_array_mut()
+            .context("Accounts file has no accounts")?;
+        for account in accounts {
+            account["data_dir"] = new_data_dir().into();
+        }
+        j["version"] = 2.into();
+    }
+    Ok(version)
+}
+
+/// Appends `suffix` to the file name of `file`.
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut file = OsString::from(file);
+    file.push(suffix);
+    file.into()
+}
+
+/// Writes `data` to the new `file` with `permissions`, replacing an existing file.
+async fn write_new_file(file: &Path, data: &[u8], permissions: u32) -> anyhow::Result<()> {
+    _ = tokio::fs::remove_file(file).await;
+    let mut f = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(permissions)
+        .open(file)
+        .await?;
+    f.write_all(data).await?;
+    f.sync_all().await?;
+    Ok(())
+}
+
 pub struct Accounts {
     accounts: HashMap<u32, Account>,
+    pending: HashSet<u32>,
+    passphrase: Option<String>,
+    loaded_plaintext: bool,
+    loaded_version: u32,
 }
 
 impl Accounts {
     pub fn new() -> Self {
         Accounts {
             accounts: HashMap::new(),
+            pending: HashSet::new(),
+            passphrase: None,
+            loaded_plaintext: false,
+            loaded_version: ACCOUNTS_FILE_VERSION,
         }
     }
 
+    /// Sets the passphrase used to encrypt the accounts file.
+    pub fn set_passphrase(&mut self, passphrase: Option<String>) {
+        self.passphrase = passphrase;
+    }
+
+    /// Checks if the loaded accounts file is plaintext but should be encrypted.
+    pub fn needs_encryption(&self) -> bool {
+        self.loaded_plaintext && self.passphrase.is_some()
+    }
+
+    /// Checks if the loaded accounts file was migrated from an older version.
+    pub fn needs_migration(&self) -> bool {
+        self.loaded_version < ACCOUNTS_FILE_VERSION
+    }
+
     fn get_free_account_id(&self) -> u32 {
         for id in 0..u32::MAX {
             if !self.accounts.contains_key(&id) {
@@ -71,8 +265,31 @@ impl Accounts {
         account
     }
 
+    /// Replaces the existing account with the same id as `account`.
+    pub fn update(&mut self, account: Account) {
+        if let Some(a) = self.accounts.get_mut(&account.id) {
+            *a = account;
+        }
+    }
+
+    /// Marks the account as pending, i.e., it is not saved until it is confirmed.
+    pub fn set_pending(&mut self, id: u32) {
+        self.pending.insert(id);
+    }
+
+    /// Checks if the account is pending.
+    pub fn is_pending(&self, id: &u32) -> bool {
+        self.pending.contains(id)
+    }
+
+    /// Confirms the pending account, returns whether it was pending.
+    pub fn confirm(&mut self, id: &u32) -> bool {
+        self.pending.remove(id)
+    }
+
     pub fn remove(&mut self, id: &u32) {
         self.accounts.remove(id);
+        self.pending.remove(id);
     }
 
     pub fn list(&self) -> Vec<Account> {
@@ -85,17 +302,40 @@ impl Accounts {
         self.accounts.get(id)
     }
 
+    /// Saves the accounts to `file`. The file is replaced atomically and the previous file is
+    /// kept as backup file with suffix ".bak".
     pub async fn save(&self, file: &Path, permissions: u32) -> anyhow::Result<()> {
-        let accounts = self.list();
-        let j = serde_json::to_vec(&accounts)?;
-        let mut file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .mode(permissions)
-            .open(file)
-            .await?;
-        file.write_all(&j).await?;
+        let mut accounts = self.list();
+        accounts.retain(|a| !self.is_pending(&a.id));
+        let accounts_file = AccountsFile {
+            version: ACCOUNTS_FILE_VERSION,
+            accounts,
+        };
+        let mut j = serde_json::to_vec(&accounts_file)?;
+        if let Some(passphrase) = &self.passphrase {
+            j = crypto::encrypt(passphrase, &j)?;
+        }
+
+        // write temporary file
+        let tmp_file = with_suffix(file, ".tmp");
+        write_new_file(&tmp_file, &j, permissions).await?;
+
+        // keep backup of previous file, replace file with temporary file
+        if tokio::fs::try_exists(file).await? {
+            let backup_file = with_suffix(file, ".bak");
+            let previous = tokio::fs::read(file).await?;
+            match &self.passphrase {
+                // do not keep a plaintext backup of an encrypted accounts file
+                Some(passphrase) if !crypto::is_encrypted(&previous) => {
+                    let previous = crypto::encrypt(passphrase, &previous)?;
+                    write_new_file(&backup_file, &previous, permissions).await?;
+                }
+                _ => {
+                    tokio::fs::copy(file, backup_file).await?;
+                }
+            }
+        }
+        tokio::fs::rename(&tmp_file, file).await?;
         Ok(())
     }
 
@@ -103,18 +343,79 @@ impl Accounts {
         let mut file = File::open(file).await?;
         let mut j = vec![];
         file.read_to_end(&mut j).await?;
-        let accounts: Vec<Account> = serde_json::from_slice(&j)?;
-        for account in accounts {
+        self.loaded_plaintext = !crypto::is_encrypted(&j);
+        if !self.loaded_plaintext {
+            let Some(passphrase) = &self.passphrase else {
+                anyhow::bail!("Accounts file is encrypted, but no passphrase is set");
+            };
+            j = crypto::decrypt(passphrase, &j)?;
+        }
+        let mut j: serde_json::Value = serde_json::from_slice(&j)?;
+        self.loaded_version = migrate_accounts_file(&mut j)?;
+        let accounts_file: AccountsFile = serde_json::from_value(j)?;
+        // check all accounts first to not load the accounts partially
+        for account in &accounts_file.accounts {
+            if account.data_dir.is_empty()
+                || !account.data_dir.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                anyhow::bail!("Invalid data directory of account {}", account.id);
+            }
+        }
+        for account in accounts_file.accounts {
             self.accounts.insert(account.id, account);
         }
         Ok(())
     }
+
+    /// Moves the data directories of the accounts in the working directory `dir` from the
+    /// layout before accounts file version 2 to their new data directories.
+    pub async fn migrate_data_dirs(&self, dir: &Path) {
+        if self.loaded_version >= 2 {
+            return;
+        }
+        for account in self.accounts.values() {
+            let Some(legacy_path) = account.legacy_data_path(dir) else {
+                warn!(
+                    account.id,
+                    "Not migrating data directory of account with invalid user"
+                );
+                continue;
+            };
+            if !tokio::fs::try_exists(&legacy_path).await.unwrap_or(false) {
+                continue;
+            }
+            let path = account.data_path(dir);
+            info!(account.id, from = %legacy_path.to_string_lossy(), to = %path.to_string_lossy(), "Migrating data directory");
+            if let Err(error) = tokio::fs::rename(&legacy_path, &path).await {
+                warn!(account.id, %error, "Could not migrate data directory");
+                continue;
+            }
+            // remove server directory if it is empty now
+            if let Some(server_path) = legacy_path.parent() {
+                _ = tokio::fs::remove_dir(server_path).await;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_account_split_user() {
+        let mut account = Account::new(0, "matrix".into(), "".into(), "".into());
+        for (user, expected) in [
+            ("alice@example.org", ("alice", "example.org")),
+            ("@alice:example.org", ("alice", "example.org")),
+            ("@alice:localhost:8008", ("alice", "localhost:8008")),
+            ("alice@localhost:8008", ("alice", "localhost:8008")),
+        ] {
+            account.user = user.into();
+            assert_eq!(account.split_user(), (expected.0.into(), expected.1.into()));
+        }
+    }
+
     #[test]
     fn test_get_free_account_id() {
         let mut accounts = Accounts::new();
@@ -215,4 +516,175 @@ mod tests {
         accounts.load(&file).await.unwrap();
         assert_eq!(accounts.list(), list);
     }
+
+    #[test]
+    fn test_account_enabled_default() {
+        let j = r#"{"id":0,"protocol":"matrix","user":"test-user1","password":"test-password1",
+            "db_passphrase":"test-passphrase","secret_store_key":""}"#;
+        let account: Account = serde_json::from_str(j).unwrap();
+        assert!(account.enabled);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_save_backup_migrate() {
+        // create temporary dir for accounts file
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("accounts.json");
+        let backup_file = tmp_dir.path().join("accounts.json.bak");
+
+        // load accounts file version 0
+        let j = r#"[{"id":0,"protocol":"matrix","user":"test-user1","password":"test-password1",
+            "db_passphrase":"test-passphrase","secret_store_key":""}]"#;
+        tokio::fs::write(&file, j).await.unwrap();
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap();
+        assert!(accounts.needs_migration());
+        assert_eq!(accounts.list()[0].user, "test-user1");
+
+        // save migrated accounts file, check backup of version 0
+        accounts.save(&file, 0o600).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&backup_file).await.unwrap(), j);
+        let mut loaded = Accounts::new();
+        loaded.load(&file).await.unwrap();
+        assert!(!loaded.needs_migration());
+        assert_eq!(loaded.list(), accounts.list());
+
+        // load unsupported future version
+        let j = r#"{"version":1000,"accounts":[]}"#;
+        tokio::fs::write(&file, j).await.unwrap();
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap_err();
+
+        // load accounts file with invalid data dir, check no account is loaded
+        let j = r#"{"version":2,"accounts":[
+            {"id":0,"protocol":"matrix","user":"test-user1","db_passphrase":"p","data_dir":"a1"},
+            {"id":1,"protocol":"matrix","user":"test-user2","db_passphrase":"p","data_dir":"../a"}]}"#;
+        tokio::fs::write(&file, j).await.unwrap();
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap_err();
+        assert!(accounts.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_accounts_migrate_data_dirs() {
+        // create temporary working dir with accounts file version 1 and old data dirs
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("accounts.json");
+        let j = r#"{"version":1,"accounts":[
+            {"id":0,"protocol":"matrix","user":"test-user1@example.org","password":"",
+            "db_passphrase":"test-passphrase"},
+            {"id":1,"protocol":"matrix","user":"../test-user2@example.org","password":"",
+            "db_passphrase":"test-passphrase"}]}"#;
+        tokio::fs::write(&file, j).await.unwrap();
+        let legacy_path = tmp_dir.path().join("data/example.org/test-user1");
+        tokio::fs::create_dir_all(legacy_path.join("db"))
+            .await
+            .unwrap();
+        let escaping_path = tmp_dir.path().join("test-user2");
+        tokio::fs::create_dir_all(&escaping_path).await.unwrap();
+
+        // load accounts, migrate data dirs
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap();
+        assert!(accounts.needs_migration());
+        let list = accounts.list();
+        assert_ne!(list[0].data_dir, list[1].data_dir);
+        assert_eq!(list[1].legacy_data_path(tmp_dir.path()), None);
+        accounts.migrate_data_dirs(tmp_dir.path()).await;
+        assert!(list[0].data_path(tmp_dir.path()).join("db").exists());
+        assert!(!legacy_path.exists());
+        assert!(!tmp_dir.path().join("data/example.org").exists());
+        assert!(escaping_path.exists());
+
+        // load accounts file with invalid data dir
+        let j = r#"{"version":2,"accounts":[{"id":0,"protocol":"matrix","user":"test-user1",
+            "password":"","db_passphrase":"test-passphrase","data_dir":"../test"}]}"#;
+        tokio::fs::write(&file, j).await.unwrap();
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_accounts_save_pending() {
+        // create temporary dir for accounts file
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("accounts.json");
+
+        // add pending account, save accounts, load accounts without pending account
+        let mut accounts = Accounts::new();
+        let account = accounts.add(
+            "matrix".into(),
+            "test-user1".into(),
+            "test-password1".into(),
+        );
+        accounts.set_pending(account.id);
+        accounts.save(&file, 0o600).await.unwrap();
+        let mut loaded = Accounts::new();
+        loaded.load(&file).await.unwrap();
+        assert!(loaded.list().is_empty());
+
+        // confirm pending account, save accounts, load accounts with confirmed account
+        assert!(accounts.confirm(&account.id));
+        assert!(!accounts.confirm(&account.id));
+        accounts.save(&file, 0o600).await.unwrap();
+        let mut loaded = Accounts::new();
+        loaded.load(&file).await.unwrap();
+        assert_eq!(loaded.list(), vec![account]);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_save_load_encrypted() {
+        // create temporary dir for accounts file
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("accounts.json");
+
+        // save plaintext accounts
+        let mut accounts = Accounts::new();
+        accounts.add(
+            "matrix".into(),
+            "test-user1".into(),
+            "test-password1".into(),
+        );
+        let list = accounts.list();
+        accounts.save(&file, 0o600).await.unwrap();
+
+        // load plaintext accounts with passphrase, migrate to encrypted
+        let mut accounts = Accounts::new();
+        accounts.set_passphrase(Some("test-passphrase".into()));
+        accounts.load(&file).await.unwrap();
+        assert_eq!(accounts.list(), list);
+        assert!(accounts.needs_encryption());
+        let plaintext = tokio::fs::read(&file).await.unwrap();
+        accounts.save(&file, 0o600).await.unwrap();
+        let data = tokio::fs::read(&file).await.unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("test-password1"));
+
+        // check no plaintext remains and the backup of plaintext accounts is encrypted
+        let mut entries = tokio::fs::read_dir(tmp_dir.path()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let data = tokio::fs::read(entry.path()).await.unwrap();
+            assert!(!String::from_utf8_lossy(&data).contains("test-password1"));
+        }
+        let backup = tokio::fs::read(tmp_dir.path().join("accounts.json.bak"))
+            .await
+            .unwrap();
+        assert_eq!(
+            crypto::decrypt("test-passphrase", &backup).unwrap(),
+            plaintext
+        );
+
+        // load encrypted accounts without and with wrong passphrase
+        let mut accounts = Accounts::new();
+        accounts.load(&file).await.unwrap_err();
+        let mut accounts = Accounts::new();
+        accounts.set_passphrase(Some("wrong-passphrase".into()));
+        accounts.load(&file).await.unwrap_err();
+
+        // load encrypted accounts with passphrase
+        let mut accounts = Accounts::new();
+        accounts.set_passphrase(Some("test-passphrase".into()));
+        accounts.load(&file).await.unwrap();
+        assert_eq!(accounts.list(), list);
+        assert!(!accounts.needs_encryption());
+    }
 }
@@ -1,10 +1,13 @@
 use clap::Parser;
 use std::path::PathBuf;
+use std::time::Duration;
 
 const DIR_PERMISSIONS: &str = "700";
 const ACCOUNTS_FILE: &str = "accounts.json";
 const FILE_PERMISSIONS: &str = "600";
 
+pub const PASSPHRASE_ENV: &str = "NUQQL_MATRIXD_PASSPHRASE";
+
 const VERSION: &str = "0.1.0";
 
 #[derive(Debug, Parser)]
@@ -18,6 +21,10 @@ struct Args {
     #[clap(long, default_value = "inet")]
     af: String,
 
+    /// set status of online accounts to away if no client sent a command for MINUTES
+    #[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    away_after: Option<u64>,
+
     /// set working directory
     #[clap(long, default_value = "")]
     dir: String,
@@ -34,6 +41,12 @@ struct Args {
     #[clap(long, value_parser = parse_permissions, default_value = FILE_PERMISSIONS)]
     file_permissions: u32,
 
+    /// encrypt accounts file with a passphrase read from the environment variable
+    /// NUQQL_MATRIXD_PASSPHRASE, the file descriptor set with --passphrase-fd or
+    /// prompted on startup
+    #[clap(long)]
+    encrypt_accounts: bool,
+
     /// enable filtering of own messages
     #[clap(long)]
     filter_own: bool,
@@ -46,10 +59,18 @@ struct Args {
     #[clap(long, default_value_t = 32000)]
     port: u16,
 
+    /// read passphrase of encrypted accounts file from file descriptor
+    #[clap(long)]
+    passphrase_fd: Option<u32>,
+
     /// push accounts to client
     #[clap(long)]
     push_accounts: bool,
 
+    /// refuse sending messages to encrypted chats with unverified devices
+    #[clap(long)]
+    refuse_unverified: bool,
+
     /// set AF_UNIX socket file in working directory
     #[clap(long, default_value = "nuqql-matrix.sock")]
     sockfile: String,
@@ -63,19 +84,39 @@ fn parse_permissions(string: &str) -> anyhow::Result<u32> {
     Ok(perm)
 }
 
+/// Reads the passphrase of the accounts file from the environment, the file descriptor or
+/// the terminal.
+fn read_passphrase(fd: Option<u32>) -> anyhow::Result<String> {
+    let passphrase = if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        passphrase
+    } else if let Some(fd) = fd {
+        let passphrase = std::fs::read_to_string(format!("/dev/fd/{fd}"))?;
+        passphrase.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        rpassword::prompt_password("Accounts file passphrase: ")?
+    };
+    if passphrase.is_empty() {
+        anyhow::bail!("Empty accounts file passphrase");
+    }
+    Ok(passphrase)
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub dir: PathBuf,
     pub dir_permissions: u32,
     pub accounts_file: PathBuf,
     pub accounts_file_permissions: u32,
+    pub accounts_passphrase: Option<String>,
     pub session_file_permissions: u32,
     pub db_file_permissions: u32,
     pub loglevel: String,
+    pub refuse_unverified: bool,
+    pub away_after: Option<Duration>,
 }
 
 impl Config {
-    pub fn parse() -> Self {
+    pub fn parse() -> anyhow::Result<Self> {
         // parse command line arguments
         let args = Args::parse();
 
@@ -94,15 +135,28 @@ impl Config {
         // get accounts file
         let accounts_file = dir.join(ACCOUNTS_FILE);
 
+        // get passphrase of accounts file
+        let accounts_passphrase = if args.encrypt_accounts
+            || args.passphrase_fd.is_some()
+            || std::env::var_os(PASSPHRASE_ENV).is_some()
+        {
+            Some(read_passphrase(args.passphrase_fd)?)
+        } else {
+            None
+        };
+
         // create config
-        Self {
+        Ok(Self {
             dir,
             dir_permissions: args.dir_permissions,
             accounts_file,
             accounts_file_permissions: args.file_permissions,
+            accounts_passphrase,
             session_file_permissions: args.file_permissions,
             db_file_permissions: args.file_permissions,
             loglevel: args.loglevel,
-        }
+            refuse_unverified: args.refuse_unverified,
+            away_after: args.away_after.map(|m| Duration::from_secs(m * 60)),
+        })
     }
 }
@@ -0,0 +1,108 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+const CIPHER: &str = "xchacha20poly1305";
+const KDF: &str = "pbkdf2-sha256";
+const KDF_ROUNDS: u32 = 200_000;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+/// Data encrypted with a key derived from a passphrase.
+#[derive(Deserialize, Serialize)]
+struct EncryptedData {
+    cipher: String,
+    kdf: String,
+    kdf_rounds: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Derives the encryption key from the passphrase.
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+/// Checks if `data` was created by [`encrypt`].
+pub fn is_encrypted(data: &[u8]) -> bool {
+    serde_json::from_slice::<EncryptedData>(data).is_ok()
+}
+
+/// Encrypts `plaintext` with an authenticated cipher using a key derived from `passphrase`.
+pub fn encrypt(passphrase: &str, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::fill(&mut salt);
+    rand::fill(&mut nonce);
+
+    let key = derive_key(passphrase, &salt, KDF_ROUNDS);
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow::anyhow!("Could not encrypt data"))?;
+
+    let data = EncryptedData {
+        cipher: CIPHER.into(),
+        kdf: KDF.into(),
+        kdf_rounds: KDF_ROUNDS,
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    };
+    Ok(serde_json::to_vec(&data)?)
+}
+
+/// Decrypts `data` created by [`encrypt`] with a key derived from `passphrase`.
+pub fn decrypt(passphrase: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let data: EncryptedData = serde_json::from_slice(data)?;
+    if data.cipher != CIPHER || data.kdf != KDF {
+        anyhow::bail!("Unsupported encryption: {} {}", data.cipher, data.kdf);
+    }
+    let salt = STANDARD.decode(data.salt)?;
+    let nonce = STANDARD.decode(data.nonce)?;
+    if nonce.len() != NONCE_LENGTH {
+        anyhow::bail!("Invalid nonce length: {}", nonce.len());
+    }
+    let ciphertext = STANDARD.decode(data.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, data.kdf_rounds);
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    cipher
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow::anyhow!("Could not decrypt data, wrong passphrase?"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let plaintext = b"[{\"password\":\"test-password\"}]";
+
+        // encrypt, check it is not plaintext
+        let data = encrypt("test-passphrase", plaintext).unwrap();
+        assert!(is_encrypted(&data));
+        assert!(!is_encrypted(plaintext));
+        assert!(!data.windows(plaintext.len()).any(|w| w == plaintext));
+
+        // decrypt with correct and wrong passphrase
+        assert_eq!(decrypt("test-passphrase", &data).unwrap(), plaintext);
+        decrypt("wrong-passphrase", &data).unwrap_err();
+
+        // decrypt tampered data
+        let mut tampered: EncryptedData = serde_json::from_slice(&data).unwrap();
+        let mut ciphertext = STANDARD.decode(&tampered.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        tampered.ciphertext = STANDARD.encode(ciphertext);
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        decrypt("test-passphrase", &tampered).unwrap_err();
+    }
+}
@@ -1,13 +1,13 @@
 use crate::account::{Account, Accounts};
 use crate::config::Config;
+use crate::idle::Idle;
 use crate::matrix::{Client, Event};
 use crate::message::Message;
 use crate::queue::Queue;
 use crate::server::{self, Server};
 use anyhow::Context;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{debug, error, info, warn};
 
 struct MatrixClient {
@@ -17,12 +17,14 @@ struct MatrixClient {
 
 struct MatrixClients {
     clients: HashMap<u32, MatrixClient>,
+    idle: watch::Receiver<bool>,
 }
 
 impl MatrixClients {
-    fn new() -> Self {
+    fn new(idle: watch::Receiver<bool>) -> Self {
         MatrixClients {
             clients: HashMap::new(),
+            idle,
         }
     }
 
@@ -46,19 +48,15 @@ impl MatrixClients {
         from_matrix: mpsc::Sender<Event>,
     ) {
         let (user, server) = account.split_user();
-        let client = Client::new(
-            config,
-            account.id,
-            &server,
-            &user,
-            &account.password,
-            &account.db_passphrase,
-            &account.secret_store_key,
-        );
+        let account_id = account.id;
+        let client = Client::new(config, account);
         let (to_matrix_tx, to_matrix_rx) = mpsc::channel(1);
+        let idle = self.idle.clone();
         tokio::spawn(async move {
-            if let Err(err) = client.start(from_matrix, to_matrix_rx).await {
-                error!(user, server, error = %err, "Could not start matrix client")
+            if let Err(err) = client.start(from_matrix.clone(), to_matrix_rx, idle).await {
+                error!(user, server, error = %err, "Could not start matrix client");
+                let event = Event::StartFailed(account_id, format!("{err:#}"));
+                _ = from_matrix.send(event).await;
             }
         });
         self.clients.insert(
@@ -70,6 +68,14 @@ impl MatrixClients {
         );
     }
 
+    /// Removes the client of the account without stopping it, e.g., if it failed to start.
+    /// A client that was started again in the meantime is kept.
+    fn remove_account(&mut self, id: u32) {
+        if self.clients.get(&id).is_some_and(|client| client.tx.is_closed()) {
+            self.clients.remove(&id);
+        }
+    }
+
     async fn stop_account(&mut self, id: u32) {
         if let Some(client) = self.clients.get(&id) {
             let (done_tx, done_rx) = oneshot::channel();
@@ -83,6 +89,26 @@ impl MatrixClients {
         }
     }
 
+    /// Logs out the client of the account on the server and removes its session and db.
+    async fn logout_account(&mut self, id: u32) -> anyhow::Result<()> {
+        let client = self
+            .clients
+            .get(&id)
+            .context("matrix client is not running")?;
+        let (done_tx, done_rx) = oneshot::channel();
+        if client.tx.send(Event::Logout(done_tx)).await.is_err() {
+            anyhow::bail!("Could not send logout event");
+        }
+        let result = done_rx.await;
+        self.clients.remove(&id);
+        result?
+    }
+
+    /// Checks if the client of the account is running.
+    fn is_running(&self, id: u32) -> bool {
+        self.clients.contains_key(&id)
+    }
+
     async fn send(&self, id: u32, event: Event) -> anyhow::Result<()> {
         if let Some(client) = self.clients.get(&id) {
             client.tx.send(event).await?
@@ -97,21 +123,40 @@ struct Daemon {
     queue: Queue,
     accounts: Accounts,
     matrix_clients: MatrixClients,
+    idle: Idle,
     done: bool,
 }
 
 impl Daemon {
     fn new(config: Config, server: Server) -> Self {
+        let mut accounts = Accounts::new();
+        accounts.set_passphrase(config.accounts_passphrase.clone());
+        let idle = Idle::new(config.away_after);
         Daemon {
             config,
             server,
             queue: Queue::new(),
-            accounts: Accounts::new(),
-            matrix_clients: MatrixClients::new(),
+            accounts,
+            matrix_clients: MatrixClients::new(idle.subscribe()),
+            idle,
             done: false,
         }
     }
 
+    /// Saves the accounts to the accounts file.
+    async fn save_accounts(&self) {
+        if let Err(err) = self
+            .accounts
+            .save(
+                &self.config.accounts_file,
+                self.config.accounts_file_permissions,
+            )
+            .await
+        {
+            error!(file = %self.config.accounts_file.to_string_lossy(), permissions=self.config.accounts_file_permissions, error = %err, "Could not save accounts to file");
+        }
+    }
+
     async fn handle_message_account_list(&mut self) -> anyhow::Result<()> {
                 let accounts = self.accounts.list();
                 for account in &accounts {
@@ -125,7 +170,11 @@ impl Daemon {
                         // or something?
                         // TODO: what about "[" and "]" around status? check other clients
                         // should we update "specs" in nuqql docs?
-                        status: self.matrix_clients.get_status(account.id).into(),
+                        status: if account.enabled {
+                            self.matrix_clients.get_status(account.id).into()
+                        } else {
+                            "disabled".into()
+                        },
                     };
                     self.queue.send(msg).await; // TODO: improve
                 }
@@ -144,63 +193,453 @@ impl Daemon {
     }
 
     async fn handle_message_account_add(
+        &mut self,
+        msg: Message,
+        from_matrix_tx: &mpsc::Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let Message::AccountAdd {
+            protocol,
+            user,
+            homeserver,
+            password,
+            password_command,
+            access_token,
+            device_id,
+            login_token,
+        } = msg
+        else {
+            return Ok(());
+        };
+        let mut account = self.accounts.add(protocol, user, password);
+        account.homeserver = homeserver;
+        account.password_command = password_command;
+        account.access_token = access_token;
+        account.device_id = device_id;
+        account.login_token = login_token;
+        self.add_account(account, from_matrix_tx).await;
+        Ok(())
+    }
+
+    async fn handle_message_account_register(
         &mut self,
         protocol: String,
         user: String,
+        homeserver: String,
         password: String,
+        token: String,
         from_matrix_tx: &mpsc::Sender<Event>,
     ) -> anyhow::Result<()> {
-                let account = self.accounts.add(protocol, user, password);
-                if account.protocol == "matrix" {
-                    self.matrix_clients.start_account(
-                        self.config.clone(),
-                        &account,
-                        from_matrix_tx.clone(),
-                    );
-                }
-                if let Err(err) = self
-                    .accounts
-                    .save(
-                        &self.config.accounts_file,
-                        self.config.accounts_file_permissions,
-                    )
-                    .await
-                {
-                    error!(file = %self.config.accounts_file.to_string_lossy(), permissions=self.config.accounts_file_permissions, error = %err, "Could not save accounts to file");
-                }
-                Ok(())
+        let mut account = self.accounts.add(protocol, user, password);
+        account.homeserver = homeserver;
+        account.registration = Some(token);
+        self.add_account(account, from_matrix_tx).await;
+        Ok(())
+    }
+
+    /// Validates the new `account` and starts its client that logs in or registers it.
+    async fn add_account(&mut self, account: Account, from_matrix_tx: &mpsc::Sender<Event>) {
+        if let Err(error) = self.validate_account(&account) {
+            self.accounts.remove(&account.id);
+            let msg = Message::error(&format!("could not add account: {error:#}"));
+            self.queue.send(msg).await; // TODO: improve
+            return;
+        }
+
+        // only save account after successful login, see handle_logged_in()
+        self.accounts.update(account.clone());
+        self.accounts.set_pending(account.id);
+        self.matrix_clients
+            .start_account(self.config.clone(), &account, from_matrix_tx.clone());
+    }
+
+    /// Checks the protocol and user of the new `account` and that it does not exist yet.
+    fn validate_account(&self, account: &Account) -> anyhow::Result<()> {
+        if account.protocol != "matrix" {
+            anyhow::bail!("unsupported protocol {}", account.protocol);
+        }
+        account.user_id()?;
+        let user = account.split_user();
+        if self
+            .accounts
+            .list()
+            .iter()
+            .any(|a| a.id != account.id && a.split_user() == user)
+        {
+            anyhow::bail!("account for user {} already exists", account.user);
+        }
+        Ok(())
+    }
+
+    /// Removes the data directory of the matrix client of the account.
+    async fn remove_account_data(&self, account: &Account) {
+        let data_folder = account.data_path(&self.config.dir);
+        if let Err(error) = tokio::fs::remove_dir_all(&data_folder).await {
+            error!(data_folder = %data_folder.to_string_lossy(), %error, "Could not remove client data directory");
+        }
+    }
+
+    /// Handles a successful login of the account's matrix client.
+    async fn handle_logged_in(&mut self, account_id: u32) {
+        // new account is valid, save it
+        if self.accounts.confirm(&account_id) {
+            info!(account_id, "Added account");
+            self.save_accounts().await;
+            let msg = Message::info(&format!("added account {account_id}."));
+            self.queue.send(msg).await; // TODO: improve
+        }
+        self.forget_credentials(account_id).await;
+    }
+
+    /// Handles a failed start of the account's matrix client.
+    async fn handle_start_failed(&mut self, account_id: u32, error: String) {
+        // client is not running any more, e.g., if logging in again failed
+        self.matrix_clients.remove_account(account_id);
+
+        // new account is not valid, remove it
+        if self.accounts.is_pending(&account_id) {
+            if let Some(account) = self.accounts.get(&account_id).cloned() {
+                self.remove_account_data(&account).await;
+            }
+            self.accounts.remove(&account_id);
+            let msg = Message::error(&format!("could not add account: {error}"));
+            self.queue.send(msg).await; // TODO: improve
+            return;
+        }
+        let msg = Message::error(&format!(
+            "account {account_id}: could not start matrix client: {error}"
+        ));
+        self.queue.send(msg).await; // TODO: improve
     }
 
     async fn handle_message_account_delete(&mut self, account_id: u32) -> anyhow::Result<()> {
-                if let Some(account) = self.accounts.get(&account_id)
+                if let Some(account) = self.accounts.get(&account_id).cloned()
                 {
-                    // stop client
+                    // log out and stop client
+                    if let Err(error) = self.matrix_clients.logout_account(account_id).await {
+                        error!(account_id, %error, "Could not log out account");
+                        let msg = Message::error(&format!(
+                            "account {account_id}: could not log out, the session and \
+                            device stay on the server: {error:#}"
+                        ));
+                        self.queue.send(msg).await; // TODO: improve
+                    }
                     self.matrix_clients.stop_account(account_id).await;
 
                     // remove client data files
-                    let (user, server) = account.split_user();
-                    let data_folder: PathBuf = ["data", &server, &user].iter().collect();
-                    let data_folder = self.config.dir.join(data_folder);
-                    if let Err(error) = tokio::fs::remove_dir_all(&data_folder).await {
-                        error!(data_folder = %data_folder.to_string_lossy(), %error, "Could not remove client data directory");
-                    }
+                    self.remove_account_data(&account).await;
 
                     // remove account
                     self.accounts.remove(&account_id);
-                    if let Err(err) = self
-                        .accounts
-                        .save(
-                            &self.config.accounts_file,
-                            self.config.accounts_file_permissions,
-                        )
-                        .await
-                    {
-                        error!(file = %self.config.accounts_file.to_string_lossy(), permissions=self.config.accounts_file_permissions, error = %err, "Could not save accounts to file");
-                    }
+                    self.save_accounts().await;
                 }
                 Ok(())
     }
 
+    async fn handle_message_account_logout(&mut self, account_id: u32) -> anyhow::Result<()> {
+        let msg = match self.matrix_clients.logout_account(account_id).await {
+            Ok(()) => Message::info(&format!("logged out account {account_id}.")),
+            Err(error) => {
+                error!(account_id, %error, "Could not log out account");
+                Message::error(&format!(
+                    "could not log out account {account_id}: {error:#}"
+                ))
+            }
+        };
+        self.queue.send(msg).await; // TODO: improve
+        Ok(())
+    }
+
+    async fn handle_message_account_password(
+        &mut self,
+        account_id: u32,
+        password: String,
+        from_matrix_tx: &mpsc::Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            let msg = Message::error(&format!("unknown account {account_id}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        };
+        account.password = password.clone();
+        self.accounts.update(account.clone());
+        self.save_accounts().await;
+
+        if self.matrix_clients.get_status(account_id) != "offline" {
+            // client is logged in, only update its password for later logins
+            let msg = Message::AccountPassword {
+                account_id,
+                password,
+            };
+            if let Err(error) = self
+                .matrix_clients
+                .send(account_id, Event::Message(msg))
+                .await
+            {
+                error!(%error, "Could not send account password message");
+            }
+            if account.forget_password {
+                self.forget_credentials(account_id).await;
+            }
+        } else if account.protocol == "matrix" && account.enabled {
+            // restart client to log in with the new password
+            self.matrix_clients.stop_account(account_id).await;
+            self.matrix_clients.start_account(
+                self.config.clone(),
+                &account,
+                from_matrix_tx.clone(),
+            );
+        }
+        let msg = Message::info(&format!("set password of account {account_id}."));
+        self.queue.send(msg).await; // TODO: improve
+        Ok(())
+    }
+
+    async fn handle_message_account_forget_password(
+        &mut self,
+        account_id: u32,
+    ) -> anyhow::Result<()> {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            let msg = Message::error(&format!("unknown account {account_id}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        };
+        account.forget_password = true;
+        self.accounts.update(account);
+        self.save_accounts().await;
+
+        // client is already logged in, forget password now
+        if self.matrix_clients.get_status(account_id) != "offline" {
+            self.forget_credentials(account_id).await;
+        }
+        let msg = Message::info(&format!(
+            "account {account_id} forgets its password after logging in."
+        ));
+        self.queue.send(msg).await; // TODO: improve
+        Ok(())
+    }
+
+    async fn handle_message_account_set(
+        &mut self,
+        account_id: u32,
+        field: String,
+        value: String,
+        from_matrix_tx: &mpsc::Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            let msg = Message::error(&format!("unknown account {account_id}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        };
+
+        // message for updating the running client
+        let update = Message::AccountSet {
+            account_id,
+            field: field.clone(),
+            value: value.clone(),
+        };
+
+        // update account, check if client needs a restart
+        let restart = match field.as_str() {
+            "password" => {
+                return self
+                    .handle_message_account_password(account_id, value, from_matrix_tx)
+                    .await;
+            }
+            "password-command" => {
+                // the command is only used for logging in, restart only logged out clients
+                account.password_command = value;
+                self.matrix_clients.get_status(account_id) == "offline"
+            }
+            "homeserver" => {
+                account.homeserver = value;
+                true
+            }
+            "forget-password" if value == "on" || value == "off" => {
+                account.forget_password = value == "on";
+                false
+            }
+            "auto-accept-invites" => {
+                account.auto_accept_invites = value.split_whitespace().map(Into::into).collect();
+                false
+            }
+            "reject-unknown-invites" if value == "on" || value == "off" => {
+                account.reject_unknown_invites = value == "on";
+                false
+            }
+            "display-name" => {
+                // display name is stored on the server, let client set it
+                if !self.matrix_clients.is_running(account_id) {
+                    let msg = Message::error(&format!(
+                        "could not set {field} of account {account_id}: client is not running"
+                    ));
+                    self.queue.send(msg).await; // TODO: improve
+                    return Ok(());
+                }
+                if let Err(error) = self
+                    .matrix_clients
+                    .send(account_id, Event::Message(update))
+                    .await
+                {
+                    error!(%error, "Could not send account set message");
+                }
+                return Ok(());
+            }
+            _ => {
+                let msg = Message::error(&format!(
+                    "could not set {field} of account {account_id}: invalid field or value"
+                ));
+                self.queue.send(msg).await; // TODO: improve
+                return Ok(());
+            }
+        };
+        self.accounts.update(account.clone());
+        self.save_accounts().await;
+
+        if restart && account.protocol == "matrix" && account.enabled {
+            self.matrix_clients.stop_account(account_id).await;
+            self.matrix_clients.start_account(
+                self.config.clone(),
+                &account,
+                from_matrix_tx.clone(),
+            );
+        } else {
+            // update running client without restarting it
+            if matches!(
+                field.as_str(),
+                "password-command" | "auto-accept-invites" | "reject-unknown-invites"
+            ) && let Err(error) = self
+                .matrix_clients
+                .send(account_id, Event::Message(update))
+                .await
+            {
+                error!(%error, "Could not send account set message");
+            }
+            if account.forget_password && self.matrix_clients.get_status(account_id) != "offline"
+            {
+                // client is already logged in, forget password now
+                self.forget_credentials(account_id).await;
+            }
+        }
+        let msg = Message::info(&format!("set {field} of account {account_id}."));
+        self.queue.send(msg).await; // TODO: improve
+        Ok(())
+    }
+
+    /// Enables or disables the account and starts or stops its client.
+    async fn handle_message_account_enable(
+        &mut self,
+        account_id: u32,
+        enabled: bool,
+        from_matrix_tx: &mpsc::Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            let msg = Message::error(&format!("unknown account {account_id}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        };
+        if account.enabled != enabled {
+            account.enabled = enabled;
+            self.accounts.update(account.clone());
+            self.save_accounts().await;
+
+            if enabled && account.protocol == "matrix" {
+                self.matrix_clients.start_account(
+                    self.config.clone(),
+                    &account,
+                    from_matrix_tx.clone(),
+                );
+            } else {
+                self.matrix_clients.stop_account(account_id).await;
+            }
+        }
+        let msg = if enabled {
+            Message::info(&format!("enabled account {account_id}."))
+        } else {
+            Message::info(&format!("disabled account {account_id}."))
+        };
+        self.queue.send(msg).await; // TODO: improve
+        Ok(())
+    }
+
+    /// Lets the client of the account set the status on the server, see handle_status_set().
+    async fn handle_message_status_set(
+        &mut self,
+        account_id: String,
+        status: String,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let Some(id) = account_id
+            .parse::<u32>()
+            .ok()
+            .filter(|id| self.accounts.get(id).is_some())
+        else {
+            let msg = Message::error(&format!("unknown account {account_id}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        };
+        if Client::convert_status_to_presence(&status).is_none() {
+            let msg = Message::error(&format!("invalid status {status}"));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        }
+        if !self.matrix_clients.is_running(id) {
+            let msg = Message::error(&format!(
+                "could not set status of account {id}: client is not running"
+            ));
+            self.queue.send(msg).await; // TODO: improve
+            return Ok(());
+        }
+
+        let msg = Message::StatusSet {
+            account_id,
+            status,
+            message,
+        };
+        if let Err(error) = self.matrix_clients.send(id, Event::Message(msg)).await {
+            error!(%error, "Could not send status set message");
+        }
+        Ok(())
+    }
+
+    /// Handles the status set by the user after the client applied it and persists it.
+    async fn handle_status_set(&mut self, account_id: u32, status: String, message: String) {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            return;
+        };
+        account.status = status.clone();
+        account.status_message = message;
+        self.accounts.update(account);
+        self.save_accounts().await;
+        let msg = Message::info(&format!("set status of account {account_id} to {status}."));
+        self.queue.send(msg).await; // TODO: improve
+    }
+
+    /// Removes the tokens of the account that are not needed any more after logging in and
+    /// the password if it should be forgotten after logging in.
+    async fn forget_credentials(&mut self, account_id: u32) {
+        let Some(mut account) = self.accounts.get(&account_id).cloned() else {
+            return;
+        };
+        let forget_password = account.forget_password && !account.password.is_empty();
+        if !forget_password
+            && account.access_token.is_empty()
+            && account.login_token.is_empty()
+            && account.registration.is_none()
+        {
+            return;
+        }
+        if forget_password {
+            info!(account_id, "Forgetting password of account");
+            account.password.clear();
+        }
+        // the session file contains the tokens now
+        account.access_token.clear();
+        account.login_token.clear();
+        account.registration = None;
+        self.accounts.update(account);
+        self.save_accounts().await;
+    }
+
     async fn handle_message(
         &mut self,
         msg: Message,
@@ -229,16 +668,59 @@ impl Daemon {
             Message::AccountList => {
                 self.handle_message_account_list().await
             }
-            Message::AccountAdd {
+            msg @ Message::AccountAdd { .. } => {
+                self.handle_message_account_add(msg, from_matrix_tx).await
+            }
+            Message::AccountRegister {
                 protocol,
                 user,
+                homeserver,
                 password,
+                token,
             } => {
-                self.handle_message_account_add(protocol, user, password, from_matrix_tx).await
+                self.handle_message_account_register(
+                    protocol,
+                    user,
+                    homeserver,
+                    password,
+                    token,
+                    from_matrix_tx,
+                )
+                .await
             }
             Message::AccountDelete { account_id } => {
                 self.handle_message_account_delete(account_id).await
             }
+            Message::AccountLogout { account_id } => {
+                self.handle_message_account_logout(account_id).await
+            }
+            Message::AccountPassword {
+                account_id,
+                password,
+            } => {
+                self.handle_message_account_password(account_id, password, from_matrix_tx)
+                    .await
+            }
+            Message::AccountForgetPassword { account_id } => {
+                self.handle_message_account_forget_password(account_id)
+                    .await
+            }
+            Message::AccountEnable { account_id } => {
+                self.handle_message_account_enable(account_id, true, from_matrix_tx)
+                    .await
+            }
+            Message::AccountDisable { account_id } => {
+                self.handle_message_account_enable(account_id, false, from_matrix_tx)
+                    .await
+            }
+            Message::AccountSet {
+                account_id,
+                field,
+                value,
+            } => {
+                self.handle_message_account_set(account_id, field, value, from_matrix_tx)
+                    .await
+            }
 
             Message::MessageCollect { account_id: _ } => {
                 let msg = Message::error("history is not supported");
@@ -295,19 +777,13 @@ impl Daemon {
                 Ok(())
             }
 
-            Message::StatusSet { account_id, status } => {
-                if let Ok(id) = account_id.parse::<u32>()
-                    && let Err(error) = self
-                        .matrix_clients
-                        .send(
-                            id,
-                            Event::Message(Message::StatusSet { account_id, status }),
-                        )
-                        .await
-                {
-                    error!(%error, "Could not send status set message");
-                }
-                Ok(())
+            Message::StatusSet {
+                account_id,
+                status,
+                message,
+            } => {
+                self.handle_message_status_set(account_id, status, message)
+                    .await
             }
 
             Message::ChatList { account_id } => {
@@ -322,12 +798,23 @@ impl Daemon {
                 Ok(())
             }
 
-            Message::ChatJoin { account_id, chat } => {
+            Message::ChatJoin {
+                account_id,
+                chat,
+                via,
+            } => {
                 info!("Received chat join message");
                 if let Ok(id) = account_id.parse::<u32>()
                     && let Err(error) = self
                         .matrix_clients
-                        .send(id, Event::Message(Message::ChatJoin { account_id, chat }))
+                        .send(
+                            id,
+                            Event::Message(Message::ChatJoin {
+                                account_id,
+                                chat,
+                                via,
+                            }),
+                        )
                         .await
                 {
                     error!(%error, "Could not send chat join message");
@@ -336,12 +823,23 @@ impl Daemon {
                 Ok(())
             }
 
-            Message::ChatLeave { account_id, chat } => {
+            Message::ChatLeave {
+                account_id,
+                chat,
+                forget,
+            } => {
                 info!("Received chat leave message");
                 if let Ok(id) = account_id.parse::<u32>()
                     && let Err(error) = self
                         .matrix_clients
-                        .send(id, Event::Message(Message::ChatLeave { account_id, chat }))
+                        .send(
+                            id,
+                            Event::Message(Message::ChatLeave {
+                                account_id,
+                                chat,
+                                forget,
+                            }),
+                        )
                         .await
                 {
                     error!(%error, "Could not send chat leave message");
@@ -415,6 +913,121 @@ impl Daemon {
                 Ok(())
             }
 
+            Message::ChatInfoGet { account_id, chat } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(
+                            id,
+                            Event::Message(Message::ChatInfoGet { account_id, chat }),
+                        )
+                        .await
+                {
+                    error!(%error, "Could not send chat info message");
+                }
+                Ok(())
+            }
+
+            Message::ChatCreate {
+                account_id,
+                name,
+                invite,
+                encrypted,
+                public,
+            } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(
+                            id,
+                            Event::Message(Message::ChatCreate {
+                                account_id,
+                                name,
+                                invite,
+                                encrypted,
+                                public,
+                            }),
+                        )
+                        .await
+                {
+                    error!(%error, "Could not send chat create message");
+                }
+                Ok(())
+            }
+
+            Message::DirectChatCreate { account_id, user } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(
+                            id,
+                            Event::Message(Message::DirectChatCreate { account_id, user }),
+                        )
+                        .await
+                {
+                    error!(%error, "Could not send direct chat create message");
+                }
+                Ok(())
+            }
+
+            Message::DeviceList { account_id } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(id, Event::Message(Message::DeviceList { account_id }))
+                        .await
+                {
+                    error!(%error, "Could not send device list message");
+                }
+                Ok(())
+            }
+
+            Message::DeviceRename {
+                account_id,
+                device_id,
+                name,
+            } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(
+                            id,
+                            Event::Message(Message::DeviceRename {
+                                account_id,
+                                device_id,
+                                name,
+                            }),
+                        )
+                        .await
+                {
+                    error!(%error, "Could not send device rename message");
+                }
+                Ok(())
+            }
+
+            Message::DeviceDelete {
+                account_id,
+                devices,
+                password,
+            } => {
+                if let Ok(id) = account_id.parse::<u32>()
+                    && let Err(error) = self
+                        .matrix_clients
+                        .send(
+                            id,
+                            Event::Message(Message::DeviceDelete {
+                                account_id,
+                                devices,
+                                password,
+                            }),
+                        )
+                        .await
+                {
+                    error!(%error, "Could not send device delete message");
+                }
+                Ok(())
+            }
+
             _ => {
                 self.queue.send(msg).await; // TODO: improve
                 Ok(())
@@ -424,7 +1037,26 @@ impl Daemon {
 
     async fn run(&mut self) -> anyhow::Result<()> {
         if let Err(err) = self.accounts.load(&self.config.accounts_file).await {
+            // do not overwrite an existing accounts file that could not be loaded
+            if tokio::fs::try_exists(&self.config.accounts_file)
+                .await
+                .unwrap_or(true)
+            {
+                return Err(err).context("Could not load accounts from file");
+            }
             warn!(file = %self.config.accounts_file.to_string_lossy(), error = %err, "Could not load accounts from file");
+        } else if self.accounts.needs_encryption() || self.accounts.needs_migration() {
+            // migrate plaintext or old accounts file to encrypted or current accounts file
+            info!(file = %self.config.accounts_file.to_string_lossy(), "Migrating accounts file");
+            // save new data dir names before moving the old data dirs
+            self.accounts
+                .save(
+                    &self.config.accounts_file,
+                    self.config.accounts_file_permissions,
+                )
+                .await
+                .context("Could not save migrated accounts file")?;
+            self.accounts.migrate_data_dirs(&self.config.dir).await;
         }
 
         // create channel for matrix events
@@ -433,7 +1065,7 @@ impl Daemon {
         // start accounts
         // TODO: move/improve?
         for account in self.accounts.list() {
-            if account.protocol != "matrix" {
+            if account.protocol != "matrix" || !account.enabled {
                 continue;
             }
             self.matrix_clients.start_account(
@@ -465,11 +1097,13 @@ impl Daemon {
                         continue;
                     }
                     self.queue.set_client(Some(c)).await;
+                    self.idle.activity();
                 },
 
                 // handle message from client
                 Some(msg) = self.queue.get_message() => match msg {
                     Some(msg) => {
+                        self.idle.activity();
                         if let Err(err) = self.handle_message(msg, &from_matrix_tx).await {
                             // client broken?
                             error!(error = %err, "Error handling message");
@@ -484,13 +1118,24 @@ impl Daemon {
                     }
                 },
 
+                // set accounts away if client is idle
+                _ = self.idle.wait() => {
+                    info!("Client is idle, setting accounts away");
+                    self.idle.set_idle();
+                }
+
                 // handle matrix event
                 Some(event) = from_matrix_rx.recv() => {
                     info!(?event, "Received matrix event");
                     match event {
                         Event::Message(msg) => self.queue.send(msg).await,
                         Event::Status(id, status) => self.matrix_clients.set_status(id, status),
-                        Event::Stop(_) => (),
+                        Event::StatusSet(id, status, message) => {
+                            self.handle_status_set(id, status, message).await
+                        }
+                        Event::LoggedIn(id) => self.handle_logged_in(id).await,
+                        Event::StartFailed(id, error) => self.handle_start_failed(id, error).await,
+                        Event::Stop(_) | Event::Logout(_) => (),
                     }
                 }
             }
@@ -0,0 +1,98 @@
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Tracks the activity of the nuqql client and notifies the matrix clients if it is idle, i.e.,
+/// if it did not connect or send a command for `away_after`.
+pub struct Idle {
+    away_after: Option<Duration>,
+    last_activity: Instant,
+    idle: watch::Sender<bool>,
+}
+
+impl Idle {
+    pub fn new(away_after: Option<Duration>) -> Self {
+        Idle {
+            away_after,
+            last_activity: Instant::now(),
+            idle: watch::Sender::new(false),
+        }
+    }
+
+    /// Subscribes to changes of the idle state.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.idle.subscribe()
+    }
+
+    /// Checks if the client is idle.
+    pub fn is_idle(&self) -> bool {
+        *self.idle.borrow()
+    }
+
+    /// Handles activity of the client, it is not idle any more.
+    pub fn activity(&mut self) {
+        self.last_activity = Instant::now();
+        self.idle
+            .send_if_modified(|idle| std::mem::replace(idle, false));
+    }
+
+    /// Waits until the client is idle. Waits forever if the client is already idle or
+    /// `away_after` is not set.
+    pub async fn wait(&self) {
+        match self.away_after {
+            Some(away_after) if !self.is_idle() => {
+                tokio::time::sleep_until(self.last_activity + away_after).await
+            }
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Sets the client idle.
+    pub fn set_idle(&self) {
+        self.idle
+            .send_if_modified(|idle| !std::mem::replace(idle, true));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle() {
+        let mut idle = Idle::new(Some(Duration::from_secs(300)));
+        let mut rx = idle.subscribe();
+
+        // not idle before timeout
+        let wait = tokio::time::timeout(Duration::from_secs(299), idle.wait());
+        wait.await.unwrap_err();
+        assert!(!idle.is_idle());
+
+        // activity restarts timeout
+        idle.activity();
+        let start = Instant::now();
+        idle.wait().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(300));
+
+        // idle -> away
+        idle.set_idle();
+        rx.changed().await.unwrap();
+        assert!(*rx.borrow_and_update());
+
+        // no more waiting while idle
+        let wait = tokio::time::timeout(Duration::from_secs(3600), idle.wait());
+        wait.await.unwrap_err();
+
+        // activity -> online
+        idle.activity();
+        rx.changed().await.unwrap();
+        assert!(!*rx.borrow_and_update());
+        idle.activity();
+        assert!(!rx.has_changed().unwrap());
+
+        // disabled
+        let idle = Idle::new(None);
+        let wait = tokio::time::timeout(Duration::from_secs(3600), idle.wait());
+        wait.await.unwrap_err();
+    }
+}
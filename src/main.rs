@@ -3,7 +3,9 @@
 
 mod account;
 mod config;
+mod crypto;
 mod daemon;
+mod idle;
 mod matrix;
 mod message;
 mod queue;
@@ -13,7 +15,7 @@ use config::Config;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Config::parse();
+    let config = Config::parse()?;
     let filter = tracing_subscriber::EnvFilter::new(&config.loglevel);
     tracing_subscriber::fmt::fmt()
         .with_env_filter(filter)
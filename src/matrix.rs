@@ -1,29 +1,178 @@
-use crate::config::Config;
+use crate::account::Account;
+use crate::config::{Config, PASSPHRASE_ENV};
 use crate::message::Message;
+use anyhow::Context;
+use futures_util::StreamExt;
 use matrix_sdk::{
     authentication::matrix::MatrixSession,
     config::SyncSettings,
-    event_handler::Ctx,
-    ruma::api::client::filter::FilterDefinition,
+    encryption::{BackupDownloadStrategy, EncryptionSettings},
+    event_handler::{Ctx, EventHandlerHandle},
+    ruma::api::client::{
+        account::register,
+        filter::FilterDefinition,
+        presence::set_presence,
+        room::{Visibility, create_room},
+        uiaa,
+    },
+    ruma::events::presence::PresenceEvent,
+    ruma::events::room::encrypted::{EncryptedEventScheme, OriginalSyncRoomEncryptedEvent},
+    ruma::events::room::encryption::RoomEncryptionEventContent,
+    ruma::events::room::member::{MembershipState, StrippedRoomMemberEvent},
     ruma::events::room::message::{
         MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
     },
+    ruma::events::{
+        AnySyncMessageLikeEvent, AnySyncTimelineEvent, InitialStateEvent, SyncMessageLikeEvent,
+    },
     ruma::presence::PresenceState,
-    ruma::{RoomId, UserId},
-    Room, RoomMemberships, RoomState,
+    ruma::serde::Raw,
+    ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, RoomOrAliasId, ServerName, UserId},
+    Room, RoomMemberships, RoomState, SessionChange, SessionMeta, SessionTokens,
 };
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, oneshot};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tracing::{debug, error, info};
 use urlencoding::encode;
 
+/// Time limit for running the password command of an account.
+const PASSWORD_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of registration requests while completing the registration flow.
+const MAX_REGISTRATION_REQUESTS: usize = 10;
+
 #[derive(Debug)]
 pub enum Event {
     Message(Message),
     Stop(oneshot::Sender<()>),
+    Logout(oneshot::Sender<anyhow::Result<()>>),
     Status(u32, String),
+    /// Status and status message set by the user, sent by the client after applying them.
+    StatusSet(u32, String, String),
+    LoggedIn(u32),
+    StartFailed(u32, String),
+}
+
+type EncryptedEvent = Raw<OriginalSyncRoomEncryptedEvent>;
+
+/// Maximum number of undecryptable events kept waiting for their room keys.
+const MAX_UNDECRYPTED_EVENTS: usize = 1000;
+
+/// Reason why a logged in client stopped running.
+enum Stopped {
+    /// The client was stopped.
+    Done,
+    /// The client should log out.
+    Logout(oneshot::Sender<anyhow::Result<()>>),
+    /// The session is not valid any more and the client needs to log in again.
+    SessionInvalid { soft_logout: bool },
+}
+
+/// Room events that could not be decrypted yet with their room and megolm session, oldest
+/// first. Only the latest `MAX_UNDECRYPTED_EVENTS` events are kept.
+#[derive(Clone, Default)]
+struct Undecrypted(Arc<Mutex<VecDeque<(OwnedRoomId, String, EncryptedEvent)>>>);
+
+impl Undecrypted {
+    /// Adds an event waiting for the room key of `session_id` in `room_id`. Drops the oldest
+    /// event if there are too many events.
+    fn add(&self, room_id: OwnedRoomId, session_id: String, event: EncryptedEvent) {
+        let mut events = self.0.lock().unwrap();
+        events.push_back((room_id, session_id, event));
+        if events.len() > MAX_UNDECRYPTED_EVENTS {
+            events.pop_front();
+        }
+    }
+
+    /// Removes and returns all events waiting for the room key of `session_id` in `room_id`.
+    fn take(&self, room_id: OwnedRoomId, session_id: String) -> Vec<EncryptedEvent> {
+        let mut events = self.0.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut *events)
+            .into_iter()
+            .partition(|(r, s, _)| *r == room_id && *s == session_id);
+        *events = kept;
+        taken.into_iter().map(|(_, _, event)| event).collect()
+    }
+}
+
+/// Presence of another user with the time the user was last active.
+#[derive(Clone, Debug, PartialEq)]
+struct UserPresence {
+    presence: PresenceState,
+    currently_active: bool,
+    last_active: Option<SystemTime>,
+}
+
+impl UserPresence {
+    /// Describes when the user was last active relative to `now`, e.g., "last active 5 minutes
+    /// ago". Returns an empty string if it is not known.
+    fn last_active_info(&self, now: SystemTime) -> String {
+        if self.currently_active {
+            return "currently active".into();
+        }
+        let Some(last_active) = self.last_active else {
+            return String::new();
+        };
+        let secs = now
+            .duration_since(last_active)
+            .unwrap_or_default()
+            .as_secs();
+        let (value, unit) = match secs {
+            0..60 => (secs, "second"),
+            60..3600 => (secs / 60, "minute"),
+            3600..86400 => (secs / 3600, "hour"),
+            _ => (secs / 86400, "day"),
+        };
+        let plural = if value == 1 { "" } else { "s" };
+        format!("last active {value} {unit}{plural} ago")
+    }
+}
+
+/// Presences of other users received during sync.
+#[derive(Clone, Default)]
+struct Presences(Arc<Mutex<HashMap<OwnedUserId, UserPresence>>>);
+
+impl Presences {
+    /// Updates the presence of `user_id`, returns whether the presence state changed.
+    fn update(&self, user_id: OwnedUserId, presence: UserPresence) -> bool {
+        let mut presences = self.0.lock().unwrap();
+        let state = presence.presence.clone();
+        presences.insert(user_id, presence).map(|p| p.presence) != Some(state)
+    }
+
+    /// Gets the presence of `user_id`.
+    fn get(&self, user_id: &UserId) -> Option<UserPresence> {
+        self.0.lock().unwrap().get(user_id).cloned()
+    }
+
+    /// Gets the users with known presence.
+    fn users(&self) -> Vec<OwnedUserId> {
+        self.0.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Rules for handling invites of the account automatically.
+#[derive(Clone, Default)]
+struct InviteRules {
+    /// Users and servers whose invites are accepted.
+    auto_accept: Vec<String>,
+    /// Decline invites from users who do not share a room with the account.
+    reject_unknown: bool,
+}
+
+impl InviteRules {
+    /// Checks if invites from `user_id` are accepted automatically.
+    fn accepts(&self, user_id: &UserId) -> bool {
+        self.auto_accept
+            .iter()
+            .any(|a| a == user_id.as_str() || a == user_id.server_name().as_str())
+    }
 }
 
 pub struct Client {
@@ -31,7 +180,22 @@ pub struct Client {
     account_id: u32,
     server: String,
     user: String,
-    password: String,
+    homeserver: String,
+    /// Password of the account, can be updated while the client is running.
+    password: Mutex<String>,
+    /// Password command of the account, can be updated while the client is running.
+    password_command: Mutex<String>,
+    access_token: String,
+    device_id: String,
+    login_token: String,
+    registration: Option<String>,
+    /// Invite rules of the account, can be updated while the client is running.
+    invite_rules: Arc<Mutex<InviteRules>>,
+    presences: Presences,
+    /// Presence and status message of the account, kept when logging in again.
+    status: Mutex<(PresenceState, String)>,
+    /// Presence currently set on the server, sent with each sync to keep it.
+    sync_presence: Arc<Mutex<PresenceState>>,
 
     session_file: PathBuf,
     db_path: PathBuf,
@@ -40,31 +204,84 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(
-        config: Config,
-        account_id: u32,
-        server: &str,
-        user: &str,
-        password: &str,
-        db_passphrase: &str,
-        secret_store_key: &str,
-    ) -> Self {
-        let session_file: PathBuf = ["data", server, user, "session"].iter().collect();
-        let session_file = config.dir.join(session_file);
-        let db_path: PathBuf = ["data", server, user, "db"].iter().collect();
-        let db_path = config.dir.join(db_path);
+    pub fn new(config: Config, account: &Account) -> Self {
+        let (user, server) = account.split_user();
+        let data_path = account.data_path(&config.dir);
+        let session_file = data_path.join("session");
+        let db_path = data_path.join("db");
         Client {
             config,
-            account_id,
-            server: server.into(),
-            user: user.into(),
-            password: password.into(),
+            account_id: account.id,
+            server,
+            user,
+            homeserver: account.homeserver.clone(),
+            password: Mutex::new(account.password.clone()),
+            password_command: Mutex::new(account.password_command.clone()),
+            access_token: account.access_token.clone(),
+            device_id: account.device_id.clone(),
+            login_token: account.login_token.clone(),
+            registration: account.registration.clone(),
+            invite_rules: Arc::new(Mutex::new(InviteRules {
+                auto_accept: account.auto_accept_invites.clone(),
+                reject_unknown: account.reject_unknown_invites,
+            })),
+            presences: Presences::default(),
+            status: Mutex::new((
+                Self::convert_status_to_presence(&account.status).unwrap_or(PresenceState::Online),
+                account.status_message.clone(),
+            )),
+            sync_presence: Arc::new(Mutex::new(PresenceState::Online)),
 
             session_file,
             db_path,
-            db_passphrase: db_passphrase.into(),
-            secret_store_key: secret_store_key.into(),
+            db_passphrase: account.db_passphrase.clone(),
+            secret_store_key: account.secret_store_key.clone(),
+        }
+    }
+
+    /// Gets the password of the account, either directly or from the output of the
+    /// password command. The output of the password command is never persisted.
+    async fn get_password(&self) -> anyhow::Result<String> {
+        let password_command = self.password_command.lock().unwrap().clone();
+        if password_command.is_empty() {
+            let password = self.password.lock().unwrap().clone();
+            if password.is_empty() {
+                anyhow::bail!(
+                    "password required, set it with: account {} password <password>",
+                    self.account_id
+                );
+            }
+            return Ok(password);
+        }
+
+        info!(self.account_id, "Running password command");
+        // do not pass the passphrase of the accounts file to the command
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&password_command)
+            .env_remove(PASSPHRASE_ENV)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(PASSWORD_COMMAND_TIMEOUT, output)
+            .await
+            .context("Password command timed out")?
+            .context("Could not run password command")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Password command failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        // use first line of output as password
+        let stdout = String::from_utf8(output.stdout).context("Invalid password command output")?;
+        let password = stdout.lines().next().unwrap_or_default();
+        if password.is_empty() {
+            anyhow::bail!("Password command returned no password");
         }
+        Ok(password.into())
     }
 
     fn get_room_name_alias(room: &Room) -> (String, String) {
@@ -77,41 +294,395 @@ impl Client {
         (name, alias)
     }
 
-    async fn send_message(
+    /// Gets the other user of the direct chat `room`, if it is a direct chat with one user.
+    fn get_dm_user(room: &Room) -> Option<OwnedUserId> {
+        let targets = room.direct_targets();
+        if targets.len() != 1 {
+            return None;
+        }
+        targets.into_iter().next()?.into_user_id()
+    }
+
+    /// Converts the chat message `msg` in the direct chat `room` to a message from or to the
+    /// other user of the direct chat, so the conversation is kept with the user's buddy.
+    fn convert_dm_message(room: &Room, msg: Message) -> Message {
+        match Self::get_dm_user(room) {
+            Some(user_id) => Self::to_dm_message(msg, &user_id, room.own_user_id()),
+            None => msg,
+        }
+    }
+
+    /// Converts the chat message `msg` to a message between `user_id` and `own_user_id`.
+    fn to_dm_message(msg: Message, user_id: &UserId, own_user_id: &UserId) -> Message {
+        let Message::ChatMessage {
+            account_id,
+            timestamp,
+            sender,
+            message,
+            ..
+        } = msg
+        else {
+            return msg;
+        };
+        let destination = if sender == user_id.as_str() {
+            own_user_id.to_string()
+        } else {
+            user_id.to_string()
+        };
+        Message::Message {
+            account_id,
+            destination,
+            timestamp,
+            sender,
+            message,
+        }
+    }
+
+    /// Gets the buddy of the user `user_id`, i.e., of a direct chat or another user with known
+    /// presence in a joined chat. The buddy is named by the user ID, its alias is the display
+    /// name of the user in the direct chat or another joined chat and its status is the
+    /// presence of the user, offline if unknown.
+    async fn get_user_buddy(
+        client: &matrix_sdk::Client,
         account_id: u32,
+        user_id: OwnedUserId,
+        presences: &Presences,
+    ) -> Message {
+        let mut alias = user_id.to_string();
+        let rooms = client
+            .get_dm_room(&user_id)
+            .into_iter()
+            .chain(client.joined_rooms());
+        for room in rooms {
+            if let Ok(Some(member)) = room.get_member_no_sync(&user_id).await {
+                if let Some(name) = member.display_name() {
+                    alias = encode(name).into();
+                }
+                break;
+            }
+        }
+        let status = match presences.get(&user_id) {
+            Some(presence) => Self::convert_presence_to_status(&presence.presence),
+            None => "offline".into(),
+        };
+        Message::Buddy {
+            account_id: account_id.to_string(),
+            status,
+            name: user_id.to_string(),
+            alias,
+        }
+    }
+
+    /// Gets the joined room `chat`.
+    fn get_joined_room(client: &matrix_sdk::Client, chat: &str) -> anyhow::Result<Room> {
+        let room_id = RoomId::parse(chat)?;
+        let room = client.get_room(&room_id).context("unknown chat")?;
+        if room.state() != RoomState::Joined {
+            anyhow::bail!("chat not joined");
+        }
+        Ok(room)
+    }
+
+    async fn send_message(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
         client: &matrix_sdk::Client,
         chat: String,
         message: String,
     ) {
-        let Ok(room_id) = RoomId::parse(chat) else {
-            return;
-        };
-        let Some(room) = client.get_room(&room_id) else {
+        let Ok(room) = Self::get_joined_room(client, &chat) else {
             return;
         };
-        if room.state() != RoomState::Joined {
+        // if the encryption state cannot be fetched, assume the room is encrypted
+        if self.config.refuse_unverified
+            && room
+                .latest_encryption_state()
+                .await
+                .map_or(true, |state| state.is_encrypted())
+            && Self::has_unverified_devices(client, &room)
+                .await
+                .unwrap_or(true)
+        {
+            error!(
+                self.account_id,
+                room = chat,
+                "Refusing to send message to room with unverified devices"
+            );
+            let msg = Message::error(&format!(
+                "refusing to send message to chat {chat} with unverified devices"
+            ));
+            self.send_to_daemon(from_matrix, msg).await;
             return;
         }
         let content = RoomMessageEventContent::text_plain(message);
         if let Err(error) = room.send(content).await {
-            error!(account_id, %error, "Could not send message to room");
+            error!(self.account_id, %error, "Could not send message to room");
+        };
+    }
+
+    /// Gets the cross-signing verification state of a user.
+    async fn get_user_trust(client: &matrix_sdk::Client, user_id: &UserId) -> &'static str {
+        match client.encryption().get_user_identity(user_id).await {
+            Ok(Some(identity)) if identity.is_verified() => "verified",
+            Ok(Some(_)) => "unverified",
+            Ok(None) | Err(_) => "unknown",
+        }
+    }
+
+    /// Checks if any active member of the room has an unverified device.
+    async fn has_unverified_devices(
+        client: &matrix_sdk::Client,
+        room: &Room,
+    ) -> anyhow::Result<bool> {
+        for member in room.members(RoomMemberships::ACTIVE).await? {
+            let devices = client
+                .encryption()
+                .get_user_devices(member.user_id())
+                .await?;
+            if devices.devices().any(|device| !device.is_verified()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Sends the encryption state of a chat and the verification state of its members.
+    async fn chat_info(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
+        client: &matrix_sdk::Client,
+        account_id: String,
+        chat: String,
+    ) -> anyhow::Result<()> {
+        let room = Self::get_joined_room(client, &chat)?;
+        let encrypted = room.latest_encryption_state().await?.is_encrypted();
+        let (encryption, devices) = if encrypted {
+            let devices = if Self::has_unverified_devices(client, &room).await? {
+                "unverified"
+            } else {
+                "verified"
+            };
+            ("encrypted", devices)
+        } else {
+            ("unencrypted", "-")
+        };
+        let msg = Message::ChatInfo {
+            account_id: account_id.clone(),
+            chat: chat.clone(),
+            encryption: encryption.into(),
+            devices: devices.into(),
+        };
+        self.send_to_daemon(from_matrix, msg).await;
+
+        for member in room.members(RoomMemberships::ACTIVE).await? {
+            let msg = Message::ChatUserTrust {
+                account_id: account_id.clone(),
+                chat: chat.clone(),
+                user: member.user_id().into(),
+                trust: Self::get_user_trust(client, member.user_id()).await.into(),
+            };
+            self.send_to_daemon(from_matrix, msg).await;
+        }
+        Ok(())
+    }
+
+    /// Creates the room `name`, invites `invite` to it and enables encryption if `encrypted` is
+    /// set. If `public` is set, the room can be joined by anyone and is published in the room
+    /// directory.
+    async fn create_room(
+        client: &matrix_sdk::Client,
+        name: String,
+        invite: &[String],
+        encrypted: bool,
+        public: bool,
+    ) -> anyhow::Result<Room> {
+        let mut request = create_room::v3::Request::new();
+        request.name = Some(name);
+        request.invite = invite.iter().map(UserId::parse).collect::<Result<_, _>>()?;
+        if encrypted {
+            request.initial_state = vec![
+                InitialStateEvent::with_empty_state_key(
+                    RoomEncryptionEventContent::with_recommended_defaults(),
+                )
+                .to_raw_any(),
+            ];
+        }
+        if public {
+            request.preset = Some(create_room::v3::RoomPreset::PublicChat);
+            request.visibility = Visibility::Public;
+        } else {
+            request.preset = Some(create_room::v3::RoomPreset::PrivateChat);
+        }
+        Ok(client.create_room(request).await?)
+    }
+
+    /// Joins the room with the room ID or alias `chat` via the servers `via`.
+    async fn join_room(
+        client: &matrix_sdk::Client,
+        chat: &str,
+        via: &[String],
+    ) -> anyhow::Result<Room> {
+        let room_or_alias_id = RoomOrAliasId::parse(chat)?;
+        let via = via
+            .iter()
+            .map(ServerName::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Ok(room_id) = RoomId::parse(chat)
+            && let Some(room) = client.get_room(&room_id)
+            && room.state() == RoomState::Joined
+        {
+            return Ok(room);
+        }
+        Ok(client
+            .join_room_by_id_or_alias(&room_or_alias_id, &via)
+            .await?)
+    }
+
+    /// Leaves the joined room `chat` or declines the invite to it and forgets it if `forget` is
+    /// set. Returns the actions that were done.
+    async fn leave_room(
+        client: &matrix_sdk::Client,
+        chat: &str,
+        forget: bool,
+    ) -> anyhow::Result<Vec<&'static str>> {
+        let room_id = RoomId::parse(chat)?;
+        let room = client.get_room(&room_id).context("unknown chat")?;
+        let mut done = Vec::new();
+        match room.state() {
+            RoomState::Joined | RoomState::Knocked => {
+                room.leave().await?;
+                done.push("left");
+            }
+            RoomState::Invited => {
+                room.leave().await?;
+                done.push("declined invite to");
+            }
+            RoomState::Left | RoomState::Banned if !forget => {
+                anyhow::bail!("chat not joined or invited")
+            }
+            RoomState::Left | RoomState::Banned => (),
+        }
+        if forget {
+            // declined invites are forgotten automatically
+            if let Some(room) = client.get_room(&room_id)
+                && matches!(room.state(), RoomState::Left | RoomState::Banned)
+            {
+                room.forget().await?;
+            }
+            done.push("forgot");
+        }
+        Ok(done)
+    }
+
+    /// Gets the existing direct chat room with `user` or creates a new one.
+    async fn get_or_create_dm(client: &matrix_sdk::Client, user: &str) -> anyhow::Result<Room> {
+        let user_id = UserId::parse(user)?;
+        if let Some(room) = client.get_dm_room(&user_id) {
+            return Ok(room);
+        }
+        Ok(client.create_dm(&user_id).await?)
+    }
+
+    /// Sends the chat `room` like in the chat list to the daemon.
+    async fn send_chat(&self, from_matrix: &mpsc::Sender<Event>, account_id: String, room: &Room) {
+        let (chat, alias) = Self::get_room_name_alias(room);
+        let msg = Message::Chat {
+            account_id,
+            chat,
+            alias,
+            nick: self.user.clone(),
         };
+        self.send_to_daemon(from_matrix, msg).await;
     }
 
     pub async fn start(
         &self,
         from_matrix: mpsc::Sender<Event>,
         mut to_matrix: mpsc::Receiver<Event>,
+        mut idle: watch::Receiver<bool>,
     ) -> anyhow::Result<()> {
+        // device to reuse when logging in again after a soft logout
+        let mut device_id = None;
+        let mut relogin = false;
+        loop {
+            let client = self
+                .connect(&from_matrix, device_id.take(), relogin)
+                .await?;
+            let soft_logout = match self
+                .run(&client, &from_matrix, &mut to_matrix, &mut idle)
+                .await?
+            {
+                Stopped::Done => break,
+                Stopped::Logout(done) => {
+                    // notify caller about logout result
+                    if done.send(self.logout(&client).await).is_err() {
+                        error!(
+                            self.account_id,
+                            "Could not send logout result back to caller"
+                        );
+                    }
+                    break;
+                }
+                Stopped::SessionInvalid { soft_logout } => soft_logout,
+            };
+
+            // session is not valid any more, remove it and log in again
+            relogin = true;
+            self.send_status(&from_matrix, &PresenceState::Offline)
+                .await;
+            let msg = if soft_logout {
+                // keep device and db to keep the encryption keys
+                device_id = client.device_id().map(ToOwned::to_owned);
+                tokio::fs::remove_file(&self.session_file).await?;
+                format!(
+                    "account {}: session expired, logging in again.",
+                    self.account_id
+                )
+            } else {
+                tokio::fs::remove_file(&self.session_file).await?;
+                tokio::fs::remove_dir_all(&self.db_path).await?;
+                format!(
+                    "account {}: session invalidated, logging in again with a new device.",
+                    self.account_id
+                )
+            };
+            info!(
+                self.account_id,
+                soft_logout, "Session is not valid any more"
+            );
+            self.send_to_daemon(&from_matrix, Message::info(&msg)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the previous session or logs in, reusing the device `device_id` if set.
+    /// If `relogin` is set, the previous session was invalidated and the one-shot tokens of
+    /// the account are not used any more.
+    async fn connect(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
+        device_id: Option<OwnedDeviceId>,
+        relogin: bool,
+    ) -> anyhow::Result<matrix_sdk::Client> {
         // client
         let client = if self.session_file.exists() {
             self.restore_session().await?
+        } else if !relogin && let Some(token) = &self.registration {
+            self.register(token).await?
+        } else if !relogin && !self.access_token.is_empty() {
+            self.login_access_token().await?
         } else {
-            self.login().await?
+            self.login(device_id, relogin).await?
         };
         self.set_session_permissions().await?;
         self.set_db_permissions().await?;
 
+        // notify daemon about established session
+        if let Err(error) = from_matrix.send(Event::LoggedIn(self.account_id)).await {
+            error!(self.account_id, %error, "Could not send logged in event");
+        }
+
         // secret store
         if !self.secret_store_key.is_empty() {
             match client
@@ -133,66 +704,199 @@ impl Client {
             self.server, self.user, "Matrix client logged in"
         );
 
-        let mut presence = PresenceState::Online;
-        loop {
-            // client sync (incoming events from matrix)
-            let c = client.clone();
-            let account_id = self.account_id;
-            let from = from_matrix.clone();
-            let (stop_tx, stop_rx) = oneshot::channel();
-            let p = presence.clone();
-            let task =
-                tokio::spawn(async move { Self::sync(c, account_id, from, stop_rx, p).await });
-
-            // send status update to daemon
-            if let Err(error) = from_matrix
-                .send(Event::Status(
-                    self.account_id,
-                    Self::convert_presence_to_status(&presence),
-                ))
-                .await
-            {
-                error!(self.account_id, %error, "Could not send status update from client to daemon");
-            }
+        Ok(client)
+    }
 
-            // handle events (outgoing events to matrix)
-            let event = self
-                .handle_to_matrix_events(&from_matrix, &mut to_matrix, &client, &presence)
-                .await;
+    /// Runs the logged in client until it is stopped or its session is not valid any more.
+    async fn run(
+        &self,
+        client: &matrix_sdk::Client,
+        from_matrix: &mpsc::Sender<Event>,
+        to_matrix: &mut mpsc::Receiver<Event>,
+        idle: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<Stopped> {
+        // retry decryption of undecryptable events when their room keys arrive
+        let undecrypted = Undecrypted::default();
+        let retry_task = tokio::spawn(Self::retry_undecrypted(
+            client.clone(),
+            self.account_id,
+            from_matrix.clone(),
+            undecrypted.clone(),
+        ));
+
+        // set the persisted presence, sync does not change it, or away if the client is idle
+        let (presence, status_message) = self.status.lock().unwrap().clone();
+        let presence = Self::idle_presence(&presence, *idle.borrow_and_update());
+        if let Err(error) = self.set_presence(client, &presence, &status_message).await {
+            error!(self.account_id, %error, "Could not set presence");
+        }
+
+        // event handlers (incoming events from matrix), registered once for this client
+        let handles = self.add_event_handlers(client, from_matrix, &undecrypted);
+
+        // client sync
+        let c = client.clone();
+        let account_id = self.account_id;
+        let sync_presence = self.sync_presence.clone();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let task =
+            tokio::spawn(async move { Self::sync(c, account_id, sync_presence, stop_rx).await });
 
-            // stop sync task
-            if stop_tx.send(()).is_err() {
-                error!(self.account_id, "Could not send stop event to sync task");
+        // send status update to daemon
+        self.send_status(from_matrix, &presence).await;
+
+        // handle events (outgoing events to matrix) and session changes
+        let mut session_changes = client.subscribe_to_session_changes();
+        let (event, session_invalid) = tokio::select! {
+            event = self.handle_to_matrix_events(from_matrix, to_matrix, idle, client) => {
+                (event, None)
             }
-            // wait for sync task
-            if let Err(error) = task.await? {
-                error!(self.account_id, %error, "Sync task returned error");
+            soft_logout = self.watch_session(client, &mut session_changes) => {
+                (None, Some(soft_logout))
             }
+        };
 
-            match event {
-                Some(Event::Stop(stopped)) => {
-                    // notify caller that client is stopped
-                    if stopped.send(()).is_err() {
-                        error!(
-                            self.account_id,
-                            "Could not send stopped event back to caller"
-                        );
-                    }
+        // stop sync task, if it did not stop already
+        if !task.is_finished() && stop_tx.send(()).is_err() {
+            error!(self.account_id, "Could not send stop event to sync task");
+        }
+        // wait for sync task, stop retry task and remove event handlers
+        let result = task.await;
+        retry_task.abort();
+        for handle in handles {
+            client.remove_event_handler(handle);
+        }
+        if let Err(error) = result? {
+            error!(self.account_id, %error, "Sync task returned error");
+        }
+
+        let stopped = match (session_invalid, event) {
+            (Some(soft_logout), _) => Stopped::SessionInvalid { soft_logout },
+            (None, Some(Event::Stop(stopped))) => {
+                // notify caller that client is stopped
+                if stopped.send(()).is_err() {
+                    error!(
+                        self.account_id,
+                        "Could not send stopped event back to caller"
+                    );
                 }
-                Some(Event::Message(Message::StatusSet { status, .. })) => {
-                    if let Some(p) = Self::convert_status_to_presence(&status) {
-                        presence = p;
+                Stopped::Done
+            }
+            (None, Some(Event::Logout(done))) => Stopped::Logout(done),
+            // other or no event, just return
+            (None, Some(_) | None) => Stopped::Done,
+        };
+
+        Ok(stopped)
+    }
+
+    /// Watches the session of the client, persists refreshed tokens and returns if the access
+    /// token is not valid any more. Returns if the server reported a soft logout.
+    async fn watch_session(
+        &self,
+        client: &matrix_sdk::Client,
+        session_changes: &mut broadcast::Receiver<SessionChange>,
+    ) -> bool {
+        loop {
+            match session_changes.recv().await {
+                Ok(SessionChange::TokensRefreshed) => {
+                    debug!(self.account_id, "Access token refreshed");
+                    if let Err(error) = self.save_session(client).await {
+                        error!(self.account_id, %error, "Could not save refreshed session");
                     }
                 }
-                // other or no event, just return
-                Some(_) | None => break,
+                Ok(SessionChange::UnknownToken(data)) => {
+                    info!(
+                        self.account_id,
+                        data.soft_logout, "Access token is not valid any more"
+                    );
+                    return data.soft_logout;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => (),
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
             }
         }
+    }
 
+    /// Sets the presence of the account on the server with the optional status message and
+    /// keeps it in the following syncs.
+    async fn set_presence(
+        &self,
+        client: &matrix_sdk::Client,
+        presence: &PresenceState,
+        status_message: &str,
+    ) -> anyhow::Result<()> {
+        *self.sync_presence.lock().unwrap() = presence.clone();
+        let user_id = client.user_id().context("Client is not logged in")?;
+        let mut request = set_presence::v3::Request::new(user_id.to_owned(), presence.clone());
+        request.status_msg = (!status_message.is_empty()).then(|| status_message.into());
+        client.send(request).await?;
         Ok(())
     }
 
-    fn convert_status_to_presence(status: &str) -> Option<PresenceState> {
+    /// Sends the status of the account to the daemon.
+    async fn send_status(&self, from_matrix: &mpsc::Sender<Event>, presence: &PresenceState) {
+        if let Err(error) = from_matrix
+            .send(Event::Status(
+                self.account_id,
+                Self::convert_presence_to_status(presence),
+            ))
+            .await
+        {
+            error!(self.account_id, %error, "Could not send status update from client to daemon");
+        }
+    }
+
+    /// Returns the presence to set on the server: away instead of online if the nuqql client
+    /// is idle.
+    fn idle_presence(presence: &PresenceState, idle: bool) -> PresenceState {
+        if idle && *presence == PresenceState::Online {
+            return PresenceState::Unavailable;
+        }
+        presence.clone()
+    }
+
+    /// Sets the presence of the account away if the nuqql client is idle and back to the
+    /// persisted presence if it is active again. Only online accounts are changed.
+    async fn set_idle(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
+        client: &matrix_sdk::Client,
+        idle: bool,
+    ) {
+        let (presence, status_message) = self.status.lock().unwrap().clone();
+        if presence != PresenceState::Online {
+            return;
+        }
+        let presence = Self::idle_presence(&presence, idle);
+        if let Err(error) = self.set_presence(client, &presence, &status_message).await {
+            error!(self.account_id, %error, "Could not set idle presence");
+            return;
+        }
+        self.send_status(from_matrix, &presence).await;
+    }
+
+    /// Receives the next event for the client and handles idle changes of the nuqql client
+    /// while waiting.
+    async fn recv_event(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
+        to_matrix: &mut mpsc::Receiver<Event>,
+        idle: &mut watch::Receiver<bool>,
+        client: &matrix_sdk::Client,
+    ) -> Option<Event> {
+        loop {
+            tokio::select! {
+                event = to_matrix.recv() => return event,
+                Ok(()) = idle.changed() => {
+                    let idle = *idle.borrow_and_update();
+                    self.set_idle(from_matrix, client, idle).await;
+                }
+            }
+        }
+    }
+
+    pub fn convert_status_to_presence(status: &str) -> Option<PresenceState> {
         match status {
             "online" => Some(PresenceState::Online),
             "away" => Some(PresenceState::Unavailable),
@@ -215,33 +919,58 @@ impl Client {
         &self,
         from_matrix: &mpsc::Sender<Event>,
         to_matrix: &mut mpsc::Receiver<Event>,
+        idle: &mut watch::Receiver<bool>,
         client: &matrix_sdk::Client,
-        presence: &PresenceState,
     ) -> Option<Event> {
-        while let Some(msg) = to_matrix.recv().await {
+        while let Some(msg) = self.recv_event(from_matrix, to_matrix, idle, client).await {
             info!(
                 self.account_id,
                 "Received event message to be handled by matrix"
             );
             match msg {
-                Event::Message(Message::BuddyList { account_id, .. }) => {
+                Event::Message(Message::BuddyList { account_id, status }) => {
+                    let online = status == "online";
+                    // list users of direct chats and other users with known presence once
+                    let mut users = HashSet::new();
+                    let mut msgs = Vec::new();
                     for room in client.rooms() {
                         let state = room.state();
                         if state != RoomState::Joined && state != RoomState::Invited {
                             continue;
                         }
+                        if state == RoomState::Joined
+                            && let Some(user_id) = Self::get_dm_user(&room)
+                        {
+                            users.insert(user_id);
+                            continue;
+                        }
                         let status = if state == RoomState::Joined {
                             "GROUP_CHAT".to_string()
                         } else {
                             "GROUP_CHAT_INVITE".to_string()
                         };
                         let (name, alias) = Self::get_room_name_alias(&room);
-                        let msg = Message::Buddy {
+                        msgs.push(Message::Buddy {
                             account_id: account_id.to_string(),
                             status,
                             name,
                             alias,
-                        };
+                        });
+                    }
+                    users.extend(self.presences.users());
+                    for user_id in users {
+                        let msg =
+                            Self::get_user_buddy(client, account_id, user_id, &self.presences)
+                                .await;
+                        // only filter users, keep group chats and invites
+                        if online
+                            && !matches!(&msg, Message::Buddy { status, .. } if status == "online")
+                        {
+                            continue;
+                        }
+                        msgs.push(msg);
+                    }
+                    for msg in msgs {
                         if let Err(error) = from_matrix.send(Event::Message(msg)).await {
                             error!(self.account_id, %error, "Could not send message event");
                         };
@@ -253,14 +982,34 @@ impl Client {
                     message,
                     ..
                 }) => {
-                    Self::send_message(self.account_id, client, destination, message).await;
+                    // send messages to users to their direct chat
+                    let chat = if destination.starts_with('@') {
+                        match Self::get_or_create_dm(client, &destination).await {
+                            Ok(room) => room.room_id().to_string(),
+                            Err(error) => {
+                                error!(self.account_id, %error, user = destination, "Could not get direct chat");
+                                let msg = Message::error(&format!(
+                                    "could not send message to {destination}: {error}"
+                                ));
+                                self.send_to_daemon(from_matrix, msg).await;
+                                continue;
+                            }
+                        }
+                    } else {
+                        destination
+                    };
+                    self.send_message(from_matrix, client, chat, message).await;
                 }
 
                 Event::Message(Message::StatusGet { account_id }) => {
                     let msg = Message::Status {
                         account_id: account_id.clone(),
                         status: if client.is_active() {
-                            Self::convert_presence_to_status(presence)
+                            let presence = &self.status.lock().unwrap().0;
+                            Self::convert_presence_to_status(&Self::idle_presence(
+                                presence,
+                                *idle.borrow(),
+                            ))
                         } else {
                             "offline".into()
                         },
@@ -270,60 +1019,101 @@ impl Client {
                     };
                 }
 
-                Event::Message(Message::StatusSet { .. }) => {
-                    return Some(msg);
+                Event::Message(Message::StatusSet {
+                    status, message, ..
+                }) => {
+                    let Some(p) = Self::convert_status_to_presence(&status) else {
+                        continue;
+                    };
+                    // keep the account away while the nuqql client is idle
+                    let presence = Self::idle_presence(&p, *idle.borrow());
+                    if let Err(error) = self.set_presence(client, &presence, &message).await {
+                        error!(self.account_id, %error, "Could not set presence");
+                        let msg =
+                            Message::error(&format!("could not set status {status}: {error}"));
+                        self.send_to_daemon(from_matrix, msg).await;
+                        continue;
+                    }
+                    self.send_status(from_matrix, &presence).await;
+                    *self.status.lock().unwrap() = (p, message.clone());
+
+                    // let daemon persist the status
+                    let event = Event::StatusSet(self.account_id, status, message);
+                    if let Err(error) = from_matrix.send(event).await {
+                        error!(self.account_id, %error, "Could not send status set event");
+                    }
                 }
 
                 Event::Message(Message::ChatList { account_id }) => {
                     for room in client.joined_rooms() {
-                        let (chat, alias) = Self::get_room_name_alias(&room);
-                        let msg = Message::Chat {
-                            account_id: account_id.clone(),
-                            chat,
-                            alias,
-                            nick: self.user.clone(),
-                        };
-                        if let Err(error) = from_matrix.send(Event::Message(msg)).await {
-                            error!(self.account_id, %error, "Could not send message event");
-                        };
+                        self.send_chat(from_matrix, account_id.clone(), &room).await;
                     }
                 }
 
-                Event::Message(Message::ChatJoin { chat, .. }) => {
-                    // TODO: send error messages?
-                    // TODO: create room
-                    let Ok(room_id) = RoomId::parse(&chat) else {
-                        continue;
-                    };
-                    let Some(room) = client.get_room(&room_id) else {
-                        continue;
-                    };
-                    if room.state() == RoomState::Joined {
-                        continue;
-                    }
-                    if let Err(error) = room.join().await {
-                        error!(self.account_id, %error, room=chat, "Could not join room");
+                Event::Message(Message::ChatCreate {
+                    account_id,
+                    name,
+                    invite,
+                    encrypted,
+                    public,
+                }) => {
+                    match Self::create_room(client, name.clone(), &invite, encrypted, public).await
+                    {
+                        Ok(room) => self.send_chat(from_matrix, account_id, &room).await,
+                        Err(error) => {
+                            error!(self.account_id, %error, name, "Could not create room");
+                            let msg =
+                                Message::error(&format!("could not create chat {name}: {error}"));
+                            self.send_to_daemon(from_matrix, msg).await;
+                        }
                     }
                 }
 
-                Event::Message(Message::ChatLeave { chat, .. }) => {
-                    // TODO: send error messages?
-                    let Ok(room_id) = RoomId::parse(&chat) else {
-                        continue;
-                    };
-                    let Some(room) = client.get_room(&room_id) else {
-                        continue;
-                    };
-                    if room.state() != RoomState::Joined || room.state() != RoomState::Invited {
-                        continue;
+                Event::Message(Message::DirectChatCreate { account_id, user }) => {
+                    match Self::get_or_create_dm(client, &user).await {
+                        Ok(room) => self.send_chat(from_matrix, account_id, &room).await,
+                        Err(error) => {
+                            error!(self.account_id, %error, user, "Could not create direct chat");
+                            let msg = Message::error(&format!(
+                                "could not create direct chat with {user}: {error}"
+                            ));
+                            self.send_to_daemon(from_matrix, msg).await;
+                        }
                     }
-                    if let Err(error) = room.join().await {
+                }
+
+                Event::Message(Message::ChatJoin {
+                    account_id,
+                    chat,
+                    via,
+                }) => match Self::join_room(client, &chat, &via).await {
+                    Ok(room) => self.send_chat(from_matrix, account_id, &room).await,
+                    Err(error) => {
                         error!(self.account_id, %error, room=chat, "Could not join room");
+                        let msg = Message::error(&format!("could not join chat {chat}: {error}"));
+                        self.send_to_daemon(from_matrix, msg).await;
+                    }
+                },
+
+                Event::Message(Message::ChatLeave { chat, forget, .. }) => {
+                    match Self::leave_room(client, &chat, forget).await {
+                        Ok(done) => {
+                            for done in done {
+                                let msg = Message::info(&format!("{done} chat {chat}."));
+                                self.send_to_daemon(from_matrix, msg).await;
+                            }
+                        }
+                        Err(error) => {
+                            error!(self.account_id, %error, room=chat, "Could not leave room");
+                            let msg =
+                                Message::error(&format!("could not leave chat {chat}: {error}"));
+                            self.send_to_daemon(from_matrix, msg).await;
+                        }
                     }
                 }
 
                 Event::Message(Message::ChatMessageSend { chat, message, .. }) => {
-                    Self::send_message(self.account_id, client, chat, message).await;
+                    self.send_message(from_matrix, client, chat, message).await;
                 }
 
                 Event::Message(Message::ChatUserList { account_id, chat }) => {
@@ -380,16 +1170,210 @@ impl Client {
                     }
                 }
 
+                Event::Message(Message::ChatInfoGet { account_id, chat }) => {
+                    if let Err(error) = self
+                        .chat_info(from_matrix, client, account_id, chat.clone())
+                        .await
+                    {
+                        error!(self.account_id, %error, room=chat, "Could not get chat info");
+                        let msg =
+                            Message::error(&format!("could not get info of chat {chat}: {error}"));
+                        self.send_to_daemon(from_matrix, msg).await;
+                    }
+                }
+
+                Event::Message(Message::DeviceList { account_id }) => {
+                    if let Err(error) = self.list_devices(from_matrix, client, account_id).await {
+                        error!(self.account_id, %error, "Could not list devices");
+                        let msg = Message::error(&format!("could not list devices: {error}"));
+                        self.send_to_daemon(from_matrix, msg).await;
+                    }
+                }
+
+                Event::Message(Message::DeviceRename {
+                    device_id, name, ..
+                }) => {
+                    let msg = match client.rename_device(device_id.as_str().into(), &name).await {
+                        Ok(_) => Message::info(&format!("renamed device {device_id}.")),
+                        Err(error) => {
+                            error!(self.account_id, %error, device_id, "Could not rename device");
+                            Message::error(&format!("could not rename device {device_id}: {error}"))
+                        }
+                    };
+                    self.send_to_daemon(from_matrix, msg).await;
+                }
+
+                Event::Message(Message::DeviceDelete {
+                    devices, password, ..
+                }) => {
+                    let msg = match self.delete_devices(client, &devices, password).await {
+                        Ok(()) => {
+                            Message::info(&format!("deleted device(s) {}.", devices.join(" ")))
+                        }
+                        Err(error) => {
+                            error!(self.account_id, %error, ?devices, "Could not delete devices");
+                            Message::error(&format!("could not delete device(s): {error}"))
+                        }
+                    };
+                    self.send_to_daemon(from_matrix, msg).await;
+                }
+
+                Event::Message(Message::AccountSet { field, value, .. })
+                    if field == "display-name" =>
+                {
+                    let msg = match client.account().set_display_name(Some(&value)).await {
+                        Ok(()) => Message::info(&format!(
+                            "set display-name of account {}.",
+                            self.account_id
+                        )),
+                        Err(error) => {
+                            error!(self.account_id, %error, "Could not set display name");
+                            Message::error(&format!("could not set display name: {error}"))
+                        }
+                    };
+                    self.send_to_daemon(from_matrix, msg).await;
+                }
+
+                Event::Message(Message::AccountSet { field, value, .. }) => {
+                    // used when logging in again or handling invites
+                    match field.as_str() {
+                        "password-command" => *self.password_command.lock().unwrap() = value,
+                        "auto-accept-invites" => {
+                            self.invite_rules.lock().unwrap().auto_accept =
+                                value.split_whitespace().map(Into::into).collect();
+                        }
+                        "reject-unknown-invites" => {
+                            self.invite_rules.lock().unwrap().reject_unknown = value == "on";
+                        }
+                        _ => (),
+                    }
+                }
+
+                Event::Message(Message::AccountPassword { password, .. }) => {
+                    // used when logging in again or deleting devices
+                    *self.password.lock().unwrap() = password;
+                }
+
                 Event::Stop(done) => {
                     return Some(Event::Stop(done));
                 }
 
+                Event::Logout(done) => {
+                    return Some(Event::Logout(done));
+                }
+
                 _ => (),
             };
         }
         None
     }
 
+    /// Builds a new matrix client with the sqlite store in db path.
+    async fn build_client(&self) -> anyhow::Result<matrix_sdk::Client> {
+        // try to fetch missing room keys from the key backup if decryption fails
+        let encryption_settings = EncryptionSettings {
+            backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+            ..Default::default()
+        };
+        let builder = if self.homeserver.is_empty() {
+            matrix_sdk::Client::builder().server_name_or_homeserver_url(&self.server)
+        } else {
+            matrix_sdk::Client::builder().homeserver_url(&self.homeserver)
+        };
+        let client = builder
+            .sqlite_store(&self.db_path, Some(&self.db_passphrase))
+            .with_encryption_settings(encryption_settings)
+            .handle_refresh_tokens()
+            .build()
+            .await?;
+        Ok(client)
+    }
+
+    /// Sends a message event to the daemon.
+    async fn send_to_daemon(&self, from_matrix: &mpsc::Sender<Event>, msg: Message) {
+        if let Err(error) = from_matrix.send(Event::Message(msg)).await {
+            error!(self.account_id, %error, "Could not send message event");
+        };
+    }
+
+    /// Lists the devices of the account with their verification state.
+    async fn list_devices(
+        &self,
+        from_matrix: &mpsc::Sender<Event>,
+        client: &matrix_sdk::Client,
+        account_id: String,
+    ) -> anyhow::Result<()> {
+        let user_id = client.user_id().context("Client is not logged in")?;
+        let response = client.devices().await?;
+        for device in response.devices {
+            let status = match client
+                .encryption()
+                .get_device(user_id, &device.device_id)
+                .await
+            {
+                Ok(Some(device)) if device.is_verified() => "verified",
+                Ok(Some(_)) => "unverified",
+                Ok(None) | Err(_) => "unknown",
+            };
+            let msg = Message::Device {
+                account_id: account_id.clone(),
+                device_id: device.device_id.to_string(),
+                name: match device.display_name {
+                    Some(name) => encode(&name).into(),
+                    None => device.device_id.to_string(),
+                },
+                last_seen_ip: device.last_seen_ip.unwrap_or("-".into()),
+                last_seen_ts: match device.last_seen_ts {
+                    Some(ts) => ts.as_secs().to_string(),
+                    None => "-".into(),
+                },
+                status: status.into(),
+            };
+            self.send_to_daemon(from_matrix, msg).await;
+        }
+        Ok(())
+    }
+
+    /// Deletes devices of the account, authenticating with `password` or, if it is empty, the
+    /// account password.
+    async fn delete_devices(
+        &self,
+        client: &matrix_sdk::Client,
+        devices: &[String],
+        password: String,
+    ) -> anyhow::Result<()> {
+        let devices: Vec<OwnedDeviceId> = devices.iter().map(|d| d.as_str().into()).collect();
+        if client
+            .device_id()
+            .is_some_and(|own| devices.iter().any(|d| d == own))
+        {
+            anyhow::bail!("cannot delete the device used by this account");
+        }
+
+        // the first request without authentication returns the auth session
+        let Err(error) = client.delete_devices(&devices, None).await else {
+            return Ok(());
+        };
+        let Some(info) = error.as_uiaa_response() else {
+            return Err(error.into());
+        };
+        let user_id = client.user_id().context("Client is not logged in")?;
+        let password = if password.is_empty() {
+            self.get_password().await?
+        } else {
+            password
+        };
+        let mut password = uiaa::Password::new(
+            uiaa::UserIdentifier::Matrix(uiaa::MatrixUserIdentifier::new(user_id.to_string())),
+            password,
+        );
+        password.session = info.session.clone();
+        client
+            .delete_devices(&devices, Some(uiaa::AuthData::Password(password)))
+            .await?;
+        Ok(())
+    }
+
     async fn restore_session(&self) -> anyhow::Result<matrix_sdk::Client> {
         info!(
             self.account_id,
@@ -402,11 +1386,7 @@ impl Client {
         let user_session: MatrixSession = serde_json::from_str(&serialized_session)?;
 
         // Build the client with the previous settings from the session.
-        let client = matrix_sdk::Client::builder()
-            .server_name_or_homeserver_url(&self.server)
-            .sqlite_store(&self.db_path, Some(&self.db_passphrase))
-            .build()
-            .await?;
+        let client = self.build_client().await?;
 
         info!(self.account_id, %user_session.meta.user_id, "Restoring session for user");
 
@@ -416,9 +1396,10 @@ impl Client {
         Ok(client)
     }
 
-    /// Login with a new device.
-    async fn login(&self) -> anyhow::Result<matrix_sdk::Client> {
-        info!(self.account_id, "No previous session found, logging in...");
+    /// Registers the user with a new device on the server and logs in. If the server requires
+    /// it, `token` is used as registration token.
+    async fn register(&self, token: &str) -> anyhow::Result<matrix_sdk::Client> {
+        info!(self.account_id, "No previous session found, registering...");
 
         // create dir with permissions
         tokio::fs::DirBuilder::new()
@@ -427,17 +1408,158 @@ impl Client {
             .create(&self.db_path)
             .await?;
 
-        let client = matrix_sdk::Client::builder()
-            .server_name_or_homeserver_url(&self.server)
-            .sqlite_store(&self.db_path, Some(&self.db_passphrase))
-            .build()
+        let client = self.build_client().await?;
+        let mut request = register::v3::Request::new();
+        request.username = Some(self.user.clone());
+        request.password = Some(self.get_password().await?);
+        request.initial_device_display_name = Some("nuqql-matrixd-rs".into());
+        request.refresh_token = true;
+
+        // complete the stages of the registration flow
+        let mut requests = 0;
+        loop {
+            let Err(error) = client.matrix_auth().register(request.clone()).await else {
+                break;
+            };
+            let Some(info) = error.as_uiaa_response() else {
+                return Err(error.into());
+            };
+            requests += 1;
+            if requests >= MAX_REGISTRATION_REQUESTS {
+                anyhow::bail!("registration failed: too many registration stages");
+            }
+            request.auth = Some(Self::get_registration_auth(info, token)?);
+        }
+
+        debug!(
+            self.account_id,
+            self.server, self.user, "Matrix client registered"
+        );
+
+        // Persist the session to reuse it later.
+        self.save_session(&client).await?;
+
+        Ok(client)
+    }
+
+    /// Gets the authentication data for the next stage of the registration flow. Only the
+    /// dummy and registration token stages are supported.
+    fn get_registration_auth(info: &uiaa::UiaaInfo, token: &str) -> anyhow::Result<uiaa::AuthData> {
+        if let Some(error) = &info.auth_error {
+            anyhow::bail!("registration failed: {}", error.message);
+        }
+        let supported = |stage: &uiaa::AuthType| match stage {
+            uiaa::AuthType::Dummy => true,
+            uiaa::AuthType::RegistrationToken => !token.is_empty(),
+            _ => false,
+        };
+        let flow = info
+            .flows
+            .iter()
+            .find(|flow| flow.stages.iter().all(supported))
+            .context("no supported registration flow, registration token required?")?;
+        let stage = flow
+            .stages
+            .iter()
+            .find(|stage| !info.completed.contains(stage))
+            .context("registration flow already completed")?;
+        let auth = if *stage == uiaa::AuthType::Dummy {
+            let mut dummy = uiaa::Dummy::new();
+            dummy.session = info.session.clone();
+            uiaa::AuthData::Dummy(dummy)
+        } else {
+            let mut registration_token = uiaa::RegistrationToken::new(token.into());
+            registration_token.session = info.session.clone();
+            uiaa::AuthData::RegistrationToken(registration_token)
+        };
+        Ok(auth)
+    }
+
+    /// Uses the existing session with the access token and device id of the account, e.g.,
+    /// created via SSO in another client, instead of logging in.
+    async fn login_access_token(&self) -> anyhow::Result<matrix_sdk::Client> {
+        info!(
+            self.account_id,
+            "No previous session found, using access token..."
+        );
+
+        // create dir with permissions
+        tokio::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.config.dir_permissions)
+            .create(&self.db_path)
+            .await?;
+
+        let client = self.build_client().await?;
+        let user_id = UserId::parse(format!("@{}:{}", self.user, self.server))?;
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: user_id.clone(),
+                device_id: self.device_id.as_str().into(),
+            },
+            tokens: SessionTokens {
+                access_token: self.access_token.clone(),
+                refresh_token: None,
+            },
+        };
+        client.restore_session(session).await?;
+
+        // make sure the access token is valid and belongs to the user and device
+        let whoami = client.whoami().await.context("Invalid access token")?;
+        if whoami.user_id != user_id
+            || whoami
+                .device_id
+                .as_ref()
+                .is_some_and(|d| d != self.device_id.as_str())
+        {
+            anyhow::bail!(
+                "Access token belongs to user {} and device {:?}",
+                whoami.user_id,
+                whoami.device_id
+            );
+        }
+
+        // Persist the session to reuse it later.
+        self.save_session(&client).await?;
+
+        Ok(client)
+    }
+
+    /// Login with a new device or the existing device `device_id`. If `relogin` is not set,
+    /// the login token of the account is used instead of the password if available.
+    async fn login(
+        &self,
+        device_id: Option<OwnedDeviceId>,
+        relogin: bool,
+    ) -> anyhow::Result<matrix_sdk::Client> {
+        info!(self.account_id, "No previous session found, logging in...");
+
+        // create dir with permissions
+        tokio::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.config.dir_permissions)
+            .create(&self.db_path)
             .await?;
+
+        let client = self.build_client().await?;
         let matrix_auth = client.matrix_auth();
 
-        matrix_auth
-            .login_username(&self.user, &self.password)
+        let password;
+        let login = if !relogin && !self.login_token.is_empty() {
+            matrix_auth.login_token(&self.login_token)
+        } else {
+            password = self.get_password().await?;
+            matrix_auth.login_username(&self.user, &password)
+        };
+        let mut login = login
             .initial_device_display_name("nuqql-matrixd-rs")
-            .await?;
+            .request_refresh_token();
+        let device_id = device_id
+            .or_else(|| (!self.device_id.is_empty()).then(|| self.device_id.as_str().into()));
+        if let Some(device_id) = &device_id {
+            login = login.device_id(device_id.as_str());
+        }
+        login.await?;
 
         debug!(
             self.account_id,
@@ -445,12 +1567,35 @@ impl Client {
         );
 
         // Persist the session to reuse it later.
+        self.save_session(&client).await?;
+
+        // After logging in, you might want to verify this session with another one (see
+        // the `emoji_verification` example), or bootstrap cross-signing if this is your
+        // first session with encryption, or if you need to reset cross-signing because
+        // you don't have access to your old sessions (see the
+        // `cross_signing_bootstrap` example).
+
+        Ok(client)
+    }
+
+    /// Logs out the client on the server, invalidating its device, and removes the session
+    /// file and db.
+    async fn logout(&self, client: &matrix_sdk::Client) -> anyhow::Result<()> {
+        info!(self.account_id, "Logging out");
+        client.logout().await?;
+        tokio::fs::remove_file(&self.session_file).await?;
+        tokio::fs::remove_dir_all(&self.db_path).await?;
+        Ok(())
+    }
+
+    /// Persists the session of the client in the session file.
+    async fn save_session(&self, client: &matrix_sdk::Client) -> anyhow::Result<()> {
         // This is not very secure, for simplicity. If the system provides a way of
         // storing secrets securely, it should be used instead.
-        // Note that we could also build the user session from the login response.
-        let user_session = matrix_auth
+        let user_session = client
+            .matrix_auth()
             .session()
-            .expect("A logged-in client should have a session");
+            .context("A logged-in client should have a session")?;
         let serialized_session = serde_json::to_vec(&user_session)?;
         let mut file = tokio::fs::OpenOptions::new()
             .write(true)
@@ -466,14 +1611,7 @@ impl Client {
             session_file = %self.session_file.to_string_lossy(),
             "Session persisted",
         );
-
-        // After logging in, you might want to verify this session with another one (see
-        // the `emoji_verification` example), or bootstrap cross-signing if this is your
-        // first session with encryption, or if you need to reset cross-signing because
-        // you don't have access to your old sessions (see the
-        // `cross_signing_bootstrap` example).
-
-        Ok(client)
+        Ok(())
     }
 
     /// Sets permissions of the session file.
@@ -507,35 +1645,60 @@ impl Client {
         Ok(())
     }
 
-    /// Setup the client to listen to new messages.
+    /// Registers the event handlers and their contexts for incoming events from matrix on
+    /// `client`. Returns the handles of the event handlers to remove them when stopping.
+    fn add_event_handlers(
+        &self,
+        client: &matrix_sdk::Client,
+        from_matrix: &mpsc::Sender<Event>,
+        undecrypted: &Undecrypted,
+    ) -> Vec<EventHandlerHandle> {
+        client.add_event_handler_context(self.account_id);
+        client.add_event_handler_context(from_matrix.clone());
+        client.add_event_handler_context(undecrypted.clone());
+        client.add_event_handler_context(self.invite_rules.clone());
+        client.add_event_handler_context(self.presences.clone());
+        vec![
+            client.add_event_handler(Self::handle_room_message),
+            client.add_event_handler(Self::handle_room_encrypted),
+            client.add_event_handler(Self::handle_invite),
+            client.add_event_handler(Self::handle_presence),
+        ]
+    }
+
+    /// Syncs the client with the server until it is stopped. Each sync carries the current
+    /// `presence`, so the server does not time it out.
     async fn sync(
         client: matrix_sdk::Client,
         account_id: u32,
-        from_matrix: mpsc::Sender<Event>,
-        stop: oneshot::Receiver<()>,
-        presence: PresenceState,
+        presence: Arc<Mutex<PresenceState>>,
+        mut stop: oneshot::Receiver<()>,
     ) -> anyhow::Result<()> {
-        info!(
-            account_id,
-            %presence, "Starting sync task for account client"
-        );
+        info!(account_id, "Starting sync task for account client");
         // Enable room members lazy-loading, it will speed up the initial sync a lot
         // with accounts in lots of rooms.
         // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
         let filter = FilterDefinition::with_lazy_loading();
-        let sync_settings = SyncSettings::default()
-            .filter(filter.into())
-            .set_presence(presence);
+        let mut sync_settings = SyncSettings::default().filter(filter.into());
 
-        client.add_event_handler_context(account_id);
-        client.add_event_handler_context(from_matrix);
-        client.add_event_handler(Self::handle_room_message);
-        tokio::select! {
-            _ = stop => (),
-            r = client.sync(sync_settings) => r?,
-        }
+        loop {
+            let settings = sync_settings
+                .clone()
+                .set_presence(presence.lock().unwrap().clone());
+            let start = tokio::time::Instant::now();
+            tokio::select! {
+                _ = &mut stop => return Ok(()),
+                r = client.sync_once(settings) => {
+                    sync_settings = sync_settings.token(r?.next_batch);
+                }
+            }
 
-        Ok(())
+            // do not hammer the server if it does not respect the sync timeout
+            tokio::select! {
+                _ = &mut stop => return Ok(()),
+                _ = tokio::time::sleep_until(start + Duration::from_secs(1)) => (),
+            }
+        }
     }
 
     async fn handle_room_message(
@@ -550,6 +1713,16 @@ impl Client {
         if room.state() != RoomState::Joined {
             return;
         }
+        Self::forward_room_message(account_id, &from_matrix, &room, event).await;
+    }
+
+    /// Forwards the text of a (decrypted) room message to the daemon.
+    async fn forward_room_message(
+        account_id: u32,
+        from_matrix: &mpsc::Sender<Event>,
+        room: &Room,
+        event: OriginalSyncRoomMessageEvent,
+    ) {
         let MessageType::Text(text_content) = event.content.msgtype else {
             return;
         };
@@ -570,17 +1743,663 @@ impl Client {
             account_id,
             "[{room_name}] {}: {}", event.sender, text_content.body
         );
-        if let Err(error) = from_matrix
-            .send(Event::Message(Message::ChatMessage {
-                account_id: account_id.to_string(),
-                chat: room.room_id().to_string(),
-                timestamp: event.origin_server_ts.as_secs().to_string(),
-                sender: event.sender.to_string(),
-                message: text_content.body,
-            }))
-            .await
+        let msg = Message::ChatMessage {
+            account_id: account_id.to_string(),
+            chat: room.room_id().to_string(),
+            timestamp: event.origin_server_ts.as_secs().to_string(),
+            sender: event.sender.to_string(),
+            message: text_content.body,
+        };
+        let msg = Self::convert_dm_message(room, msg);
+        if let Err(error) = from_matrix.send(Event::Message(msg)).await {
+            error!(account_id, %error, "Could not send message event");
+        };
+    }
+
+    /// Handles a presence update of another user and sends the new buddy status to the daemon if
+    /// the user is a buddy and its presence state changed.
+    async fn handle_presence(
+        event: PresenceEvent,
+        client: matrix_sdk::Client,
+        account_id: Ctx<u32>,
+        from_matrix: Ctx<mpsc::Sender<Event>>,
+        presences: Ctx<Presences>,
+    ) {
+        let account_id: u32 = account_id.0;
+        if client.user_id() == Some(&*event.sender) {
+            return;
+        }
+        let presence = UserPresence {
+            presence: event.content.presence,
+            currently_active: event.content.currently_active.unwrap_or(false),
+            last_active: event
+                .content
+                .last_active_ago
+                .and_then(|ago| SystemTime::now().checked_sub(Duration::from_millis(ago.into()))),
+        };
+        let last_active = presence.last_active_info(SystemTime::now());
+        if !presences.update(event.sender.clone(), presence) {
+            return;
+        }
+        debug!(account_id, user = %event.sender, "Presence of user changed");
+
+        // send new status of buddy and its last activity to daemon
+        let user = event.sender.to_string();
+        let buddy = Self::get_user_buddy(&client, account_id, event.sender, &presences).await;
+        let mut msgs = vec![buddy];
+        if !last_active.is_empty() {
+            msgs.push(Message::info(&format!(
+                "account {account_id}: {user} {last_active}."
+            )));
+        }
+        for msg in msgs {
+            if let Err(error) = from_matrix.send(Event::Message(msg)).await {
+                error!(account_id, %error, "Could not send message event");
+            };
+        }
+    }
+
+    /// Handles an invite of the account to a room, sends it to the daemon and accepts or
+    /// declines it automatically depending on the invite rules.
+    async fn handle_invite(
+        event: StrippedRoomMemberEvent,
+        room: Room,
+        client: matrix_sdk::Client,
+        account_id: Ctx<u32>,
+        from_matrix: Ctx<mpsc::Sender<Event>>,
+        invite_rules: Ctx<Arc<Mutex<InviteRules>>>,
+    ) {
+        let account_id: u32 = account_id.0;
+        if event.content.membership != MembershipState::Invite
+            || client.user_id() != Some(&*event.state_key)
+            || room.state() != RoomState::Invited
         {
+            return;
+        }
+        info!(account_id, room = %room.room_id(), inviter = %event.sender, "Received invite");
+
+        // send invite to daemon
+        let (name, alias) = Self::get_room_name_alias(&room);
+        let room_name = match room.display_name().await {
+            Ok(room_name) => room_name.to_string(),
+            Err(_) => name.clone(),
+        };
+        let msgs = [
+            Message::Buddy {
+                account_id: account_id.to_string(),
+                status: "GROUP_CHAT_INVITE".into(),
+                name: name.clone(),
+                alias,
+            },
+            Message::info(&format!(
+                "account {account_id}: {} invited you to chat {name} ({room_name}).",
+                event.sender
+            )),
+        ];
+        for msg in msgs {
+            if let Err(error) = from_matrix.send(Event::Message(msg)).await {
+                error!(account_id, %error, "Could not send message event");
+            };
+        }
+
+        // accept or decline invite, do not block sync while joining
+        let (accepts, reject_unknown) = {
+            let invite_rules = invite_rules.lock().unwrap();
+            (
+                invite_rules.accepts(&event.sender),
+                invite_rules.reject_unknown,
+            )
+        };
+        let (accept, result) = if accepts {
+            (true, "accepted")
+        } else if reject_unknown && !Self::is_known_user(&client, &event.sender).await {
+            (false, "declined")
+        } else {
+            return;
+        };
+        let from_matrix = from_matrix.0.clone();
+        tokio::spawn(async move {
+            let handled = if accept {
+                room.join().await
+            } else {
+                room.leave().await
+            };
+            let msg = match handled {
+                Ok(()) => Message::info(&format!(
+                    "account {account_id}: {result} invite to chat {name} automatically."
+                )),
+                Err(error) => {
+                    error!(account_id, %error, room = name, accept, "Could not handle invite");
+                    Message::error(&format!(
+                        "account {account_id}: could not handle invite to chat {name}: {error}"
+                    ))
+                }
+            };
+            if let Err(error) = from_matrix.send(Event::Message(msg)).await {
+                error!(account_id, %error, "Could not send message event");
+            };
+        });
+    }
+
+    /// Checks if the user shares a joined room with the account.
+    async fn is_known_user(client: &matrix_sdk::Client, user_id: &UserId) -> bool {
+        for room in client.joined_rooms() {
+            if let Ok(Some(member)) = room.get_member_no_sync(user_id).await
+                && member.membership() == &MembershipState::Join
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Creates the placeholder message shown for an undecryptable room event.
+    fn undecryptable_message(
+        account_id: u32,
+        room_id: &RoomId,
+        event: &OriginalSyncRoomEncryptedEvent,
+    ) -> Message {
+        Message::ChatMessage {
+            account_id: account_id.to_string(),
+            chat: room_id.to_string(),
+            timestamp: event.origin_server_ts.as_secs().to_string(),
+            sender: event.sender.to_string(),
+            message: format!("[unable to decrypt, waiting for keys] {}", event.event_id),
+        }
+    }
+
+    /// Handles a room event that could not be decrypted during sync.
+    async fn handle_room_encrypted(
+        raw: EncryptedEvent,
+        room: Room,
+        account_id: Ctx<u32>,
+        from_matrix: Ctx<mpsc::Sender<Event>>,
+        undecrypted: Ctx<Undecrypted>,
+    ) {
+        let account_id: u32 = account_id.0;
+        if room.state() != RoomState::Joined {
+            return;
+        }
+        let event = match raw.deserialize() {
+            Ok(event) => event,
+            Err(error) => {
+                error!(account_id, %error, "Could not parse encrypted room event");
+                return;
+            }
+        };
+        let EncryptedEventScheme::MegolmV1AesSha2(scheme) = &event.content.scheme else {
+            return;
+        };
+        info!(account_id, room = %room.room_id(), event_id = %event.event_id, "Unable to decrypt room event");
+
+        // remember event until its room key arrives
+        undecrypted.add(room.room_id().to_owned(), scheme.session_id.clone(), raw);
+
+        let msg = Self::undecryptable_message(account_id, room.room_id(), &event);
+        let msg = Self::convert_dm_message(&room, msg);
+        if let Err(error) = from_matrix.send(Event::Message(msg)).await {
             error!(account_id, %error, "Could not send message event");
         };
     }
+
+    /// Retries decryption of undecryptable events when their room keys arrive via to-device
+    /// messages or the key backup, and forwards the decrypted messages to the daemon.
+    async fn retry_undecrypted(
+        client: matrix_sdk::Client,
+        account_id: u32,
+        from_matrix: mpsc::Sender<Event>,
+        undecrypted: Undecrypted,
+    ) {
+        let Some(room_keys) = client.encryption().room_keys_received_stream().await else {
+            error!(account_id, "Could not get room keys stream");
+            return;
+        };
+        let mut room_keys = std::pin::pin!(room_keys);
+        while let Some(room_keys) = room_keys.next().await {
+            let room_keys = match room_keys {
+                Ok(room_keys) => room_keys,
+                Err(error) => {
+                    error!(account_id, %error, "Could not get received room keys");
+                    continue;
+                }
+            };
+            for room_key in room_keys {
+                let events =
+                    undecrypted.take(room_key.room_id.clone(), room_key.session_id.clone());
+                if events.is_empty() {
+                    continue;
+                }
+                let Some(room) = client.get_room(&room_key.room_id) else {
+                    continue;
+                };
+                for raw in events {
+                    let event = match room.decrypt_event(&raw, None).await {
+                        Ok(event) => event,
+                        Err(error) => {
+                            error!(account_id, %error, "Could not decrypt room event");
+                            continue;
+                        }
+                    };
+                    if event.kind.is_utd() {
+                        // still missing the right key, keep waiting
+                        undecrypted.add(room_key.room_id.clone(), room_key.session_id.clone(), raw);
+                        continue;
+                    }
+                    info!(account_id, room = %room.room_id(), "Decrypted room event after receiving room key");
+                    if let Ok(AnySyncTimelineEvent::MessageLike(
+                        AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(event)),
+                    )) = event.raw().deserialize()
+                    {
+                        Self::forward_room_message(account_id, &from_matrix, &room, event).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Accounts;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// State of the fake homeserver.
+    #[derive(Default)]
+    struct Homeserver {
+        /// Send a room message in the next sync response.
+        deliver: AtomicBool,
+        /// Reject the next sync request with a soft logout.
+        soft_logout: AtomicBool,
+        /// Number of handled sync requests.
+        syncs: AtomicUsize,
+    }
+
+    /// Reads a request from `stream` and returns its method and path.
+    async fn read_homeserver_request(stream: &mut TcpStream) -> Option<(String, String)> {
+        // read request header and body
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let header_end = loop {
+            let n = stream.read(&mut buf).await.ok().filter(|n| *n > 0)?;
+            request.extend_from_slice(&buf[..n]);
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let header = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+        let length: usize = header
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .and_then(|l| l.trim().parse().ok())
+            .unwrap_or(0);
+        while request.len() < header_end + length {
+            let n = stream.read(&mut buf).await.ok().filter(|n| *n > 0)?;
+            request.extend_from_slice(&buf[..n]);
+        }
+        let mut request_line = header.split_whitespace();
+        let method = request_line.next()?.to_uppercase();
+        let path = request_line.next()?.to_string();
+        Some((method, path))
+    }
+
+    /// Creates the status and body of the fake homeserver's response to a request.
+    async fn homeserver_response(
+        homeserver: &Homeserver,
+        method: &str,
+        path: &str,
+    ) -> (&'static str, String) {
+        let ok = "200 OK";
+        if path.starts_with("/_matrix/client/versions") {
+            return (ok, r#"{"versions":["v1.1","v1.11"]}"#.into());
+        }
+        if path.contains("/account/whoami") {
+            return (
+                ok,
+                r#"{"user_id":"@alice:localhost","device_id":"DEVICE"}"#.into(),
+            );
+        }
+        if method == "POST" && path.contains("/login") {
+            let body =
+                r#"{"user_id":"@alice:localhost","access_token":"token2","device_id":"DEVICE"}"#;
+            return (ok, body.into());
+        }
+        if path.contains("/keys/upload") {
+            return (ok, r#"{"one_time_key_counts":{}}"#.into());
+        }
+        if !path.contains("/sync") {
+            return (ok, "{}".into());
+        }
+
+        // sync
+        homeserver.syncs.fetch_add(1, Ordering::SeqCst);
+        if homeserver.soft_logout.swap(false, Ordering::SeqCst) {
+            let body = r#"{"errcode":"M_UNKNOWN_TOKEN","error":"expired","soft_logout":true}"#;
+            return ("401 Unauthorized", body.into());
+        }
+        if homeserver.deliver.swap(false, Ordering::SeqCst) {
+            let body = serde_json::json!({
+                "next_batch": "s1",
+                "rooms": {"join": {"!room:localhost": {"timeline": {"events": [{
+                    "type": "m.room.message",
+                    "event_id": "$event:localhost",
+                    "sender": "@bob:localhost",
+                    "origin_server_ts": 1700000000000u64,
+                    "content": {"msgtype": "m.text", "body": "hello"},
+                }]}}}},
+            });
+            return (ok, body.to_string());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        (ok, r#"{"next_batch":"s0"}"#.into())
+    }
+
+    /// Handles a single request to the fake homeserver.
+    async fn handle_homeserver_request(mut stream: TcpStream, homeserver: Arc<Homeserver>) {
+        let Some((method, path)) = read_homeserver_request(&mut stream).await else {
+            return;
+        };
+        let (status, body) = homeserver_response(&homeserver, &method, &path).await;
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+
+    /// Runs a fake homeserver, returns its url.
+    async fn run_homeserver(homeserver: Arc<Homeserver>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_homeserver_request(stream, homeserver.clone()));
+            }
+        });
+        url
+    }
+
+    /// Waits for the next event from the client that matches `f`.
+    async fn wait_for(from_matrix: &mut mpsc::Receiver<Event>, f: impl Fn(&Event) -> bool) {
+        tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let event = from_matrix.recv().await.expect("client stopped");
+                if f(&event) {
+                    return;
+                }
+            }
+        })
+        .await
+        .expect("timeout waiting for event");
+    }
+
+    /// Waits until the fake homeserver handled `n` more sync requests.
+    async fn wait_for_syncs(homeserver: &Homeserver, n: usize) {
+        let syncs = homeserver.syncs.load(Ordering::SeqCst) + n;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while homeserver.syncs.load(Ordering::SeqCst) < syncs {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timeout waiting for sync");
+    }
+
+    fn encrypted_event(event_id: &str, session_id: &str) -> EncryptedEvent {
+        let event = serde_json::json!({
+            "type": "m.room.encrypted",
+            "event_id": event_id,
+            "sender": "@bob:example.org",
+            "origin_server_ts": 1700000000000u64,
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "ciphertext",
+                "sender_key": "senderkey",
+                "device_id": "DEVICE",
+                "session_id": session_id,
+            },
+        });
+        Raw::from_json_string(event.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_undecryptable_message() {
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let event = encrypted_event("$event", "session").deserialize().unwrap();
+        let msg = Client::undecryptable_message(1, &room_id, &event);
+        assert_eq!(
+            msg.to_string(),
+            Message::ChatMessage {
+                account_id: "1".into(),
+                chat: "!room:example.org".into(),
+                timestamp: "1700000000".into(),
+                sender: "@bob:example.org".into(),
+                message: "[unable to decrypt, waiting for keys] $event".into(),
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_undecrypted_add_take() {
+        let undecrypted = Undecrypted::default();
+        let room = RoomId::parse("!room:example.org").unwrap();
+        let other = RoomId::parse("!other:example.org").unwrap();
+        undecrypted.add(room.clone(), "s1".into(), encrypted_event("$1", "s1"));
+        undecrypted.add(room.clone(), "s2".into(), encrypted_event("$2", "s2"));
+        undecrypted.add(other.clone(), "s1".into(), encrypted_event("$3", "s1"));
+        undecrypted.add(room.clone(), "s1".into(), encrypted_event("$4", "s1"));
+
+        // only events of the same room and session are taken, in order
+        let ids = |events: Vec<EncryptedEvent>| -> Vec<String> {
+            events
+                .iter()
+                .map(|e| e.deserialize().unwrap().event_id.to_string())
+                .collect()
+        };
+        assert_eq!(
+            ids(undecrypted.take(room.clone(), "s1".into())),
+            ["$1", "$4"]
+        );
+        assert!(undecrypted.take(room.clone(), "s1".into()).is_empty());
+        assert_eq!(ids(undecrypted.take(other, "s1".into())), ["$3"]);
+
+        // oldest events are dropped if there are too many
+        for i in 0..MAX_UNDECRYPTED_EVENTS {
+            let id = format!("$new{i}");
+            undecrypted.add(room.clone(), "s3".into(), encrypted_event(&id, "s3"));
+        }
+        assert!(undecrypted.take(room.clone(), "s2".into()).is_empty());
+        assert_eq!(
+            undecrypted.take(room, "s3".into()).len(),
+            MAX_UNDECRYPTED_EVENTS
+        );
+    }
+
+    #[test]
+    fn test_to_dm_message() {
+        let user_id = UserId::parse("@bob:example.org").unwrap();
+        let own_user_id = UserId::parse("@alice:example.org").unwrap();
+        let chat_message = |sender: &str| Message::ChatMessage {
+            account_id: "1".into(),
+            chat: "!room:example.org".into(),
+            timestamp: "1700000000".into(),
+            sender: sender.into(),
+            message: "hello".into(),
+        };
+        for (sender, destination) in [
+            ("@bob:example.org", "@alice:example.org"),
+            ("@alice:example.org", "@bob:example.org"),
+        ] {
+            assert_eq!(
+                Client::to_dm_message(chat_message(sender), &user_id, &own_user_id),
+                Message::Message {
+                    account_id: "1".into(),
+                    destination: destination.into(),
+                    timestamp: "1700000000".into(),
+                    sender: sender.into(),
+                    message: "hello".into(),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_invite_rules_accepts() {
+        let rules = InviteRules {
+            auto_accept: vec!["@alice:example.org".into(), "example.com".into()],
+            reject_unknown: false,
+        };
+        for (user, expected) in [
+            ("@alice:example.org", true),
+            ("@bob:example.org", false),
+            ("@bob:example.com", true),
+            ("@alice:example.net", false),
+        ] {
+            assert_eq!(rules.accepts(&UserId::parse(user).unwrap()), expected);
+        }
+        assert!(!InviteRules::default().accepts(&UserId::parse("@alice:example.org").unwrap()));
+    }
+
+    #[test]
+    fn test_presences_update() {
+        let presences = Presences::default();
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+
+        let presence = |presence, last_active| UserPresence {
+            presence,
+            currently_active: false,
+            last_active,
+        };
+
+        // new presence and changed presence are changes, same presence is not
+        assert_eq!(presences.get(&user_id), None);
+        assert!(presences.update(user_id.clone(), presence(PresenceState::Online, None)));
+        let online = presence(PresenceState::Online, Some(SystemTime::UNIX_EPOCH));
+        assert!(!presences.update(user_id.clone(), online.clone()));
+        assert_eq!(presences.get(&user_id), Some(online));
+        let away = presence(PresenceState::Unavailable, None);
+        assert!(presences.update(user_id.clone(), away.clone()));
+        assert_eq!(presences.get(&user_id), Some(away));
+        assert_eq!(presences.users(), [user_id]);
+    }
+
+    #[test]
+    fn test_last_active_info() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let presence = |currently_active, ago: Option<u64>| UserPresence {
+            presence: PresenceState::Online,
+            currently_active,
+            last_active: ago.map(|ago| now - Duration::from_secs(ago)),
+        };
+        assert_eq!(presence(false, None).last_active_info(now), "");
+        assert_eq!(
+            presence(true, Some(600)).last_active_info(now),
+            "currently active"
+        );
+        for (ago, info) in [
+            (0, "last active 0 seconds ago"),
+            (1, "last active 1 second ago"),
+            (59, "last active 59 seconds ago"),
+            (60, "last active 1 minute ago"),
+            (7200, "last active 2 hours ago"),
+            (86400 * 3, "last active 3 days ago"),
+        ] {
+            assert_eq!(presence(false, Some(ago)).last_active_info(now), info);
+        }
+    }
+
+    #[test]
+    fn test_idle_presence() {
+        // only online is changed to away while idle
+        let idle = |p| Client::idle_presence(&p, true);
+        assert_eq!(idle(PresenceState::Online), PresenceState::Unavailable);
+        assert_eq!(idle(PresenceState::Unavailable), PresenceState::Unavailable);
+        assert_eq!(idle(PresenceState::Offline), PresenceState::Offline);
+        let active = |p| Client::idle_presence(&p, false);
+        assert_eq!(active(PresenceState::Online), PresenceState::Online);
+        assert_eq!(
+            active(PresenceState::Unavailable),
+            PresenceState::Unavailable
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_single_delivery_after_status_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let homeserver = Arc::new(Homeserver::default());
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            dir_permissions: 0o700,
+            accounts_file: dir.path().join("accounts.json"),
+            accounts_file_permissions: 0o600,
+            accounts_passphrase: None,
+            session_file_permissions: 0o600,
+            db_file_permissions: 0o600,
+            loglevel: String::new(),
+            refuse_unverified: false,
+            away_after: None,
+        };
+        let mut account =
+            Accounts::new().add("matrix".into(), "alice@localhost".into(), "secret".into());
+        account.homeserver = run_homeserver(homeserver.clone()).await;
+        account.access_token = "token".into();
+        account.device_id = "DEVICE".into();
+
+        // start client and wait until it is running
+        let client = Arc::new(Client::new(config, &account));
+        let (from_tx, mut from_rx) = mpsc::channel(100);
+        let (to_tx, to_rx) = mpsc::channel(100);
+        let (_idle_tx, idle_rx) = watch::channel(false);
+        let c = client.clone();
+        let task = tokio::spawn(async move { c.start(from_tx, to_rx, idle_rx).await });
+        wait_for(&mut from_rx, |e| matches!(e, Event::Status(..))).await;
+
+        // change status multiple times
+        for status in ["away", "online", "away"] {
+            let msg = Message::StatusSet {
+                account_id: account.id.to_string(),
+                status: status.into(),
+                message: String::new(),
+            };
+            to_tx.send(Event::Message(msg)).await.unwrap();
+            wait_for(
+                &mut from_rx,
+                |e| matches!(e, Event::Status(_, s) if s == status),
+            )
+            .await;
+        }
+
+        // invalidate session and wait until client logged in again and runs with the status
+        homeserver.soft_logout.store(true, Ordering::SeqCst);
+        wait_for(&mut from_rx, |e| matches!(e, Event::LoggedIn(_))).await;
+        wait_for(
+            &mut from_rx,
+            |e| matches!(e, Event::Status(_, s) if s == "away"),
+        )
+        .await;
+
+        // receive message and make sure it is delivered only once, event handlers of a sync
+        // response are done when the next sync is requested
+        homeserver.deliver.store(true, Ordering::SeqCst);
+        let is_chat_message = |e: &Event| matches!(e, Event::Message(Message::ChatMessage { .. }));
+        wait_for(&mut from_rx, is_chat_message).await;
+        wait_for_syncs(&homeserver, 2).await;
+        let mut count = 1;
+        while let Ok(event) = from_rx.try_recv() {
+            if is_chat_message(&event) {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 1);
+
+        // stop client
+        let (stop_tx, stop_rx) = oneshot::channel();
+        to_tx.send(Event::Stop(stop_tx)).await.unwrap();
+        stop_rx.await.unwrap();
+        task.await.unwrap().unwrap();
+    }
 }
@@ -24,16 +24,67 @@ pub enum Message {
     AccountList,
     // add account
     // account add <protocol> <user> <password>
+    // account add <protocol> <user> command <password command>
+    // account add <protocol> <user> token <access token> <device id>
+    // account add <protocol> <user> login-token <login token>
+    // account add <protocol> <user> homeserver <url> <...>
     AccountAdd {
         protocol: String,
         user: String,
+        homeserver: String,
         password: String,
+        password_command: String,
+        access_token: String,
+        device_id: String,
+        login_token: String,
+    },
+    // register account
+    // account register <protocol> <user> [homeserver <url>] <password> [token]
+    AccountRegister {
+        protocol: String,
+        user: String,
+        homeserver: String,
+        password: String,
+        token: String,
     },
     // delete account
     // account <id> delete
     AccountDelete {
         account_id: u32,
     },
+    // log out account
+    // account <id> logout
+    AccountLogout {
+        account_id: u32,
+    },
+    // set account password
+    // account <id> password <password>
+    AccountPassword {
+        account_id: u32,
+        password: String,
+    },
+    // forget account password after login
+    // account <id> forget-password
+    AccountForgetPassword {
+        account_id: u32,
+    },
+    // set account field
+    // account <id> set <field> <value>
+    AccountSet {
+        account_id: u32,
+        field: String,
+        value: String,
+    },
+    // enable account
+    // account <id> enable
+    AccountEnable {
+        account_id: u32,
+    },
+    // disable account
+    // account <id> disable
+    AccountDisable {
+        account_id: u32,
+    },
     // buddy
     // buddy: <acc_id> status: <status> name: <name> alias: [alias]
     Buddy {
@@ -84,10 +135,11 @@ pub enum Message {
         account_id: String,
     },
     // set status
-    // account <id> status set <status>
+    // account <id> status set <status> [message]
     StatusSet {
         account_id: String,
         status: String,
+        message: String,
     },
     // chat
     // chat: list: <acc_id> <chat_id> <chat_alias> <nick>
@@ -103,16 +155,18 @@ pub enum Message {
         account_id: String,
     },
     // join chat
-    // account <id> chat join <chat>
+    // account <id> chat join <chat> [via <servers...>]
     ChatJoin {
         account_id: String,
         chat: String,
+        via: Vec<String>,
     },
     // leave chat
-    // account <id> chat part <chat>
+    // account <id> chat part <chat> [forget]
     ChatLeave {
         account_id: String,
         chat: String,
+        forget: bool,
     },
     // chat message
     // chat: msg: <acc_id> <chat> <timestamp> <sender> <message>
@@ -153,6 +207,72 @@ pub enum Message {
         chat: String,
         user: String,
     },
+    // chat info
+    // chat: info: <acc_id> <chat> <encryption> <devices>
+    ChatInfo {
+        account_id: String,
+        chat: String,
+        encryption: String,
+        devices: String,
+    },
+    // get chat info
+    // account <id> chat info <chat>
+    ChatInfoGet {
+        account_id: String,
+        chat: String,
+    },
+    // create chat
+    // account <id> chat create <name> [invite <users...>] [encrypted] [public|private]
+    ChatCreate {
+        account_id: String,
+        name: String,
+        invite: Vec<String>,
+        encrypted: bool,
+        public: bool,
+    },
+    // create direct chat
+    // account <id> dm <user>
+    DirectChatCreate {
+        account_id: String,
+        user: String,
+    },
+    // chat user trust
+    // chat: trust: <acc_id> <chat> <name> <state>
+    ChatUserTrust {
+        account_id: String,
+        chat: String,
+        user: String,
+        trust: String,
+    },
+    // device
+    // device: <acc_id> <device_id> <name> <last_seen_ip> <last_seen_ts> <state>
+    Device {
+        account_id: String,
+        device_id: String,
+        name: String,
+        last_seen_ip: String,
+        last_seen_ts: String,
+        status: String,
+    },
+    // list devices
+    // account <id> device list
+    DeviceList {
+        account_id: String,
+    },
+    // rename device
+    // account <id> device rename <device_id> <name>
+    DeviceRename {
+        account_id: String,
+        device_id: String,
+        name: String,
+    },
+    // delete devices
+    // account <id> device delete <device_id> [device_id...] [password <password>]
+    DeviceDelete {
+        account_id: String,
+        devices: Vec<String>,
+        password: String,
+    },
     // get version
     // version
     Version,
@@ -187,25 +307,93 @@ account add <protocol> <user> <password>
     the password <password>. The supported chat protocol(s) are backend
     specific. The user name is chat protocol specific. An account id is
     assigned to the account that can be shown with "account list".
+account add <protocol> <user> command <password command>
+    add a new account like above, but get the password from the output of the
+    shell command <password command> every time a login is needed, e.g.,
+    "pass show matrix/work". The password is not stored.
+account add <protocol> <user> token <access token> <device id>
+    add a new account like above, but use the existing session with the access
+    token <access token> of the device <device id> instead of logging in with
+    a password, e.g., if the server only supports SSO.
+account add <protocol> <user> login-token <login token>
+    add a new account like above, but log in once with the login token
+    <login token>, e.g., obtained via SSO, instead of a password.
+account add <protocol> <user> homeserver <url> <...>
+    add a new account like above, but connect to the homeserver at <url>,
+    e.g., "http://localhost:8008", instead of looking it up for the server
+    in <user>. For matrix, the user can be given as <name>@<server> or as
+    Matrix user ID @<name>:<server>.
+account register <protocol> <user> [homeserver <url>] <password> [token]
+    register the new user <user> with the password <password> on the server
+    and add the account like above. Optionally, use the registration token
+    [token] if the server requires it.
 account <id> delete
-    delete the account with the account id <id>.
+    log out and delete the account with the account id <id>.
+account <id> logout
+    log out the account with the account id <id> on the server and remove
+    its session and local data, but keep the account. The account logs in
+    again with a new device on restart or after setting its password.
+account <id> password <password>
+    set the password of the account with the account id <id> to <password>
+    and log in again if the account is not logged in.
+account <id> forget-password
+    remove the password of the account with the account id <id> from the
+    accounts file after logging in. The password is only needed again if the
+    session becomes invalid.
+account <id> set <field> <value>
+    set the field <field> of the account with the account id <id> to <value>.
+    Supported fields are:
+        password <password>: the password, log in again if needed.
+        password-command <command>: the password command, log in again if
+            needed. Empty <command> removes the password command.
+        homeserver <url>: the homeserver URL, reconnect to the homeserver.
+            Empty <url> looks up the homeserver for the server in the user.
+        display-name <name>: the display name of the user on the server.
+        forget-password on|off: forget the password after logging in.
+        auto-accept-invites <users/servers...>: accept invites from the users
+            like @name:server or from all users on the servers automatically.
+            Empty <users/servers...> disables accepting invites automatically.
+        reject-unknown-invites on|off: decline invites from users who do not
+            share a chat with the account automatically.
+account <id> enable
+    enable the account with the account id <id> and connect it.
+account <id> disable
+    disconnect and disable the account with the account id <id> without
+    deleting it. Disabled accounts are not connected on startup.
 account <id> buddies [online]
     list all buddies on the account with the account id <id>. Optionally, show
-    only online buddies with the extra parameter "online".
+    only online buddies with the extra parameter "online". For matrix, the
+    buddies are the users of direct chats and other users with known
+    presence in joined chats with their status online, away or offline as
+    well as the group chats and invites. The filter "online" does not remove
+    group chats and invites.
 account <id> collect
     collect all messages received on the account with the account id <id>.
 account <id> send <user> <msg>
     send a message to the user <user> on the account with the account id <id>.
+    For matrix, messages to a user @name:server are sent to the direct chat
+    with the user, which is created if it does not exist. Direct chats are
+    listed as buddies named by the user in "buddies" and their messages are
+    received as messages from or to the user.
 account <id> status get
     get the status of the account with the account id <id>.
-account <id> status set <status>
+account <id> status set <status> [message]
     set the status of the account with the account id <id> to <status>.
+    For matrix, the status is online, away or offline and, optionally, the
+    status message [message] is shown to other users. The status is kept
+    after restarts.
 account <id> chat list
     list all group chats on the account with the account id <id>.
-account <id> chat join <chat>
-    join the group chat <chat> on the account with the account id <id>.
-account <id> chat part <chat>
-    leave the group chat <chat> on the account with the account id <id>.
+account <id> chat join <chat> [via <servers...>]
+    join the group chat <chat> on the account with the account id <id>. The
+    chat is a room ID like !id:server or a room alias like #name:server.
+    Optionally, join via the servers <servers...> if the own server does not
+    know the chat yet. The joined chat is shown like in "chat list".
+account <id> chat part <chat> [forget]
+    leave the group chat <chat> or decline the invite to the group chat <chat>
+    on the account with the account id <id>. Optionally, also forget the chat
+    with the extra parameter "forget", so it is not listed in "buddies" any
+    more.
 account <id> chat send <chat> <msg>
     send the message <msg> to the group chat <chat> on the account with the
     account id <id>.
@@ -215,6 +403,27 @@ account <id> chat users <chat>
 account <id> chat invite <chat> <user>
     invite the user <user> to the group chat <chat> on the account with the
     account id <id>.
+account <id> chat info <chat>
+    show if the group chat <chat> on the account with the account id <id> is
+    encrypted and if all devices in it are verified, followed by the
+    cross-signing verification state of each user in the chat.
+account <id> chat create <name> [invite <users...>] [encrypted] [public|private]
+    create the group chat <name> on the account with the account id <id>.
+    Optionally, invite the users <users...>, enable encryption with
+    "encrypted" and make the chat public with "public". Chats are private by
+    default. The new chat is shown like in "chat list".
+account <id> dm <user>
+    create a direct chat with the user <user> on the account with the account
+    id <id> or use the existing one. The chat is shown like in "chat list".
+account <id> device list
+    list all devices of the account with the account id <id>.
+account <id> device rename <device_id> <name>
+    rename the device <device_id> of the account with the account id <id> to
+    <name>.
+account <id> device delete <device_id> [device_id...] [password <password>]
+    delete the device(s) <device_id> of the account with the account id <id>
+    using <password> or, if not set, the password stored for the account or
+    returned by its password command.
 version
     get version of the backend
 bye
@@ -267,18 +476,33 @@ fn parse(s: &str) -> Option<Message> {
         "account:" => parse_account(s),
         // account list
         // account add <protocol> <user> <password>
+        // account add <protocol> <user> command <password command>
+        // account add <protocol> <user> token <access token> <device id>
+        // account add <protocol> <user> login-token <login token>
+        // account add <protocol> <user> homeserver <url> <...>
+        // account register <protocol> <user> [homeserver <url>] <password> [token]
         // account <id> delete
+        // account <id> logout
+        // account <id> password <password>
+        // account <id> forget-password
+        // account <id> set <field> <value>
         // account <id> buddies [online]
         // account <id> collect
         // account <id> send <user> <msg>
         // account <id> status get
-        // account <id> status set <status>
+        // account <id> status set <status> [message]
         // account <id> chat list
-        // account <id> chat join <chat>
-        // account <id> chat part <chat>
+        // account <id> chat join <chat> [via <servers...>]
+        // account <id> chat part <chat> [forget]
         // account <id> chat send <chat> <msg>
         // account <id> chat users <chat>
         // account <id> chat invite <chat> <user>
+        // account <id> chat info <chat>
+        // account <id> chat create <name> [invite <users...>] [encrypted] [public|private]
+        // account <id> dm <user>
+        // account <id> device list
+        // account <id> device rename <device_id> <name>
+        // account <id> device delete <device_id> [device_id...] [password <password>]
         "account" => parse_account_command(s),
         // buddy: <acc_id> status: <status> name: <name> alias: [alias]
         "buddy:" => parse_buddy(s),
@@ -289,7 +513,11 @@ fn parse(s: &str) -> Option<Message> {
         // chat: msg: <acc_id> <chat> <timestamp> <sender> <message>
         // chat: list: <acc_id> <chat_id> <chat_alias> <nick>
         // chat: user: <acc_id> <chat> <name> <alias> <state>
+        // chat: info: <acc_id> <chat> <encryption> <devices>
+        // chat: trust: <acc_id> <chat> <name> <state>
         "chat:" => parse_chat(s),
+        // device: <acc_id> <device_id> <name> <last_seen_ip> <last_seen_ts> <state>
+        "device:" => parse_device(s),
         // version
         "version" => Some(Message::Version),
         // bye
@@ -343,18 +571,33 @@ fn parse_account(s: Vec<&str>) -> Option<Message> {
 fn parse_account_command(s: Vec<&str>) -> Option<Message> {
     // account list
     // account add <protocol> <user> <password>
+    // account add <protocol> <user> command <password command>
+    // account add <protocol> <user> token <access token> <device id>
+    // account add <protocol> <user> login-token <login token>
+    // account add <protocol> <user> homeserver <url> <...>
+    // account register <protocol> <user> [homeserver <url>] <password> [token]
     // account <id> delete
+    // account <id> logout
+    // account <id> password <password>
+    // account <id> forget-password
+    // account <id> set <field> <value>
     // account <id> buddies [online]
     // account <id> collect
     // account <id> send <user> <msg>
     // account <id> status get
-    // account <id> status set <status>
+    // account <id> status set <status> [message]
     // account <id> chat list
-    // account <id> chat join <chat>
-    // account <id> chat part <chat>
+    // account <id> chat join <chat> [via <servers...>]
+    // account <id> chat part <chat> [forget]
     // account <id> chat send <chat> <msg>
     // account <id> chat users <chat>
     // account <id> chat invite <chat> <user>
+    // account <id> chat info <chat>
+    // account <id> chat create <name> [invite <users...>] [encrypted] [public|private]
+    // account <id> dm <user>
+    // account <id> device list
+    // account <id> device rename <device_id> <name>
+    // account <id> device delete <device_id> [device_id...] [password <password>]
 
     if s.len() < 2 {
         return None;
@@ -362,16 +605,71 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
 
     // account list
     // account add <protocol> <user> <password>
+    // account add <protocol> <user> command <password command>
+    // account add <protocol> <user> token <access token> <device id>
+    // account add <protocol> <user> login-token <login token>
+    // account add <protocol> <user> homeserver <url> <...>
+    // account register <protocol> <user> [homeserver <url>] <password> [token]
     match s[1] {
         "list" => return Some(Message::AccountList),
         "add" => {
             if s.len() < 5 {
                 return None;
             }
+            // account add <protocol> <user> homeserver <url> <...>
+            let (homeserver, c) = match s[4] {
+                "homeserver" if s.len() > 6 => (s[5].into(), &s[6..]),
+                "homeserver" => return None,
+                _ => (String::new(), &s[4..]),
+            };
+            let mut password = String::new();
+            let mut password_command = String::new();
+            let mut access_token = String::new();
+            let mut device_id = String::new();
+            let mut login_token = String::new();
+            match c[0] {
+                // account add <protocol> <user> command <password command>
+                "command" if c.len() > 1 => password_command = c[1..].join(" "),
+                // account add <protocol> <user> token <access token> <device id>
+                "token" if c.len() == 3 => {
+                    access_token = c[1].into();
+                    device_id = c[2].into();
+                }
+                // account add <protocol> <user> login-token <login token>
+                "login-token" if c.len() == 2 => login_token = c[1].into(),
+                // account add <protocol> <user> <password>
+                _ => password = c[0].into(),
+            }
             return Some(Message::AccountAdd {
                 protocol: s[2].into(),
                 user: s[3].into(),
-                password: s[4].into(),
+                homeserver,
+                password,
+                password_command,
+                access_token,
+                device_id,
+                login_token,
+            });
+        }
+        "register" => {
+            if s.len() < 5 {
+                return None;
+            }
+            // account register <protocol> <user> [homeserver <url>] <password> [token]
+            let (homeserver, c) = match s[4] {
+                "homeserver" if s.len() > 6 => (s[5].into(), &s[6..]),
+                "homeserver" => return None,
+                _ => (String::new(), &s[4..]),
+            };
+            if c.len() > 2 {
+                return None;
+            }
+            return Some(Message::AccountRegister {
+                protocol: s[2].into(),
+                user: s[3].into(),
+                homeserver,
+                password: c[0].into(),
+                token: c.get(1).map(|t| t.to_string()).unwrap_or_default(),
             });
         }
         _ => (),
@@ -391,6 +689,49 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
             return Some(Message::AccountDelete { account_id });
         }
 
+        // account <id> logout
+        "logout" => {
+            return Some(Message::AccountLogout { account_id });
+        }
+
+        // account <id> password <password>
+        "password" => {
+            if s.len() < 4 {
+                return None;
+            }
+            return Some(Message::AccountPassword {
+                account_id,
+                password: s[3].into(),
+            });
+        }
+
+        // account <id> forget-password
+        "forget-password" => {
+            return Some(Message::AccountForgetPassword { account_id });
+        }
+
+        // account <id> set <field> <value>
+        "set" => {
+            if s.len() < 4 {
+                return None;
+            }
+            return Some(Message::AccountSet {
+                account_id,
+                field: s[3].into(),
+                value: s[4..].join(" "),
+            });
+        }
+
+        // account <id> enable
+        "enable" => {
+            return Some(Message::AccountEnable { account_id });
+        }
+
+        // account <id> disable
+        "disable" => {
+            return Some(Message::AccountDisable { account_id });
+        }
+
         // account <id> buddies [online]
         "buddies" => {
             return Some(Message::BuddyList {
@@ -417,7 +758,7 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
         }
 
         // account <id> status get
-        // account <id> status set <status>
+        // account <id> status set <status> [message]
         "status" => {
             if s.len() < 4 {
                 return None;
@@ -435,6 +776,7 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
                     return Some(Message::StatusSet {
                         account_id: s[1].into(),
                         status: s[4].into(),
+                        message: s[5..].join(" "),
                     });
                 }
                 _ => return None,
@@ -442,11 +784,13 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
         }
 
         // account <id> chat list
-        // account <id> chat join <chat>
-        // account <id> chat part <chat>
+        // account <id> chat join <chat> [via <servers...>]
+        // account <id> chat part <chat> [forget]
         // account <id> chat send <chat> <msg>
         // account <id> chat users <chat>
         // account <id> chat invite <chat> <user>
+        // account <id> chat info <chat>
+        // account <id> chat create <name> [invite <users...>] [encrypted] [public|private]
         "chat" => {
             if s.len() < 4 {
                 return None;
@@ -461,18 +805,32 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
                     if s.len() < 5 {
                         return None;
                     }
+                    let via = match s.get(5) {
+                        Some(&"via") if s.len() > 6 => {
+                            s[6..].iter().map(|v| v.to_string()).collect()
+                        }
+                        Some(_) => return None,
+                        None => Vec::new(),
+                    };
                     return Some(Message::ChatJoin {
                         account_id: s[1].into(),
                         chat: s[4].into(),
+                        via,
                     });
                 }
                 "part" => {
                     if s.len() < 5 {
                         return None;
                     }
+                    let forget = match s.get(5) {
+                        Some(&"forget") => true,
+                        Some(_) => return None,
+                        None => false,
+                    };
                     return Some(Message::ChatLeave {
                         account_id: s[1].into(),
                         chat: s[4].into(),
+                        forget,
                     });
                 }
                 "send" => {
@@ -504,6 +862,96 @@ fn parse_account_command(s: Vec<&str>) -> Option<Message> {
                         user: s[5].into(),
                     });
                 }
+                "info" => {
+                    if s.len() < 5 {
+                        return None;
+                    }
+                    return Some(Message::ChatInfoGet {
+                        account_id: s[1].into(),
+                        chat: s[4].into(),
+                    });
+                }
+                "create" => {
+                    if s.len() < 5 {
+                        return None;
+                    }
+                    let mut invite = Vec::new();
+                    let mut encrypted = false;
+                    let mut public = false;
+                    let mut in_invite = false;
+                    for option in &s[5..] {
+                        match *option {
+                            "invite" => in_invite = true,
+                            "encrypted" => encrypted = true,
+                            "public" => public = true,
+                            "private" => public = false,
+                            user if in_invite => invite.push(user.into()),
+                            _ => return None,
+                        }
+                    }
+                    return Some(Message::ChatCreate {
+                        account_id: s[1].into(),
+                        name: s[4].into(),
+                        invite,
+                        encrypted,
+                        public,
+                    });
+                }
+                _ => return None,
+            }
+        }
+
+        // account <id> dm <user>
+        "dm" => {
+            if s.len() < 4 {
+                return None;
+            }
+            return Some(Message::DirectChatCreate {
+                account_id: s[1].into(),
+                user: s[3].into(),
+            });
+        }
+
+        // account <id> device list
+        // account <id> device rename <device_id> <name>
+        // account <id> device delete <device_id> [device_id...] [password <password>]
+        "device" => {
+            if s.len() < 4 {
+                return None;
+            }
+            match s[3] {
+                "list" => {
+                    return Some(Message::DeviceList {
+                        account_id: s[1].into(),
+                    });
+                }
+                "rename" => {
+                    if s.len() < 6 {
+                        return None;
+                    }
+                    return Some(Message::DeviceRename {
+                        account_id: s[1].into(),
+                        device_id: s[4].into(),
+                        name: s[5..].join(" "),
+                    });
+                }
+                "delete" => {
+                    if s.len() < 5 {
+                        return None;
+                    }
+                    let (devices, password) = match s[4..].iter().position(|d| *d == "password") {
+                        Some(i) => (&s[4..4 + i], s[5 + i..].join(" ")),
+                        None => (&s[4..], String::new()),
+                    };
+                    if devices.is_empty() || (devices.len() < s.len() - 4 && password.is_empty()) {
+                        return None;
+                    }
+                    return Some(Message::DeviceDelete {
+                        account_id: s[1].into(),
+                        devices: devices.iter().map(|d| d.to_string()).collect(),
+                        password,
+                    });
+                }
                 _ => return None,
             }
         }
@@ -531,6 +979,8 @@ fn parse_chat(s: Vec<&str>) -> Option<Message> {
     // chat: msg: <acc_id> <chat> <timestamp> <sender> <message>
     // chat: list: <acc_id> <chat_id> <chat_alias> <nick>
     // chat: user: <acc_id> <chat> <name> <alias> <state>
+    // chat: info: <acc_id> <chat> <encryption> <devices>
+    // chat: trust: <acc_id> <chat> <name> <state>
     if s.len() < 6 {
         return None;
     }
@@ -565,10 +1015,37 @@ fn parse_chat(s: Vec<&str>) -> Option<Message> {
                 status: s[6].into(),
             })
         }
+        "info:" => Some(Message::ChatInfo {
+            account_id: s[2].into(),
+            chat: s[3].into(),
+            encryption: s[4].into(),
+            devices: s[5].into(),
+        }),
+        "trust:" => Some(Message::ChatUserTrust {
+            account_id: s[2].into(),
+            chat: s[3].into(),
+            user: s[4].into(),
+            trust: s[5].into(),
+        }),
         _ => None,
     }
 }
 
+fn parse_device(s: Vec<&str>) -> Option<Message> {
+    // device: <acc_id> <device_id> <name> <last_seen_ip> <last_seen_ts> <state>
+    if s.len() < 7 {
+        return None;
+    }
+    Some(Message::Device {
+        account_id: s[1].into(),
+        device_id: s[2].into(),
+        name: s[3].into(),
+        last_seen_ip: s[4].into(),
+        last_seen_ts: s[5].into(),
+        status: s[6].into(),
+    })
+}
+
 fn parse_info(s: Vec<&str>) -> Option<Message> {
     // info: <msg>
     if s.len() < 2 {
@@ -605,9 +1082,67 @@ impl std::fmt::Display for Message {
             Message::AccountAdd {
                 protocol,
                 user,
+                homeserver,
                 password: _,
-            } => write!(f, "account add {protocol} {user} REDACTED\r\n"),
+                password_command,
+                access_token,
+                device_id,
+                login_token,
+            } => {
+                write!(f, "account add {protocol} {user} ")?;
+                if !homeserver.is_empty() {
+                    write!(f, "homeserver {homeserver} ")?;
+                }
+                if !password_command.is_empty() {
+                    write!(f, "command {password_command}\r\n")
+                } else if !access_token.is_empty() {
+                    write!(f, "token REDACTED {device_id}\r\n")
+                } else if !login_token.is_empty() {
+                    write!(f, "login-token REDACTED\r\n")
+                } else {
+                    write!(f, "REDACTED\r\n")
+                }
+            }
+            Message::AccountRegister {
+                protocol,
+                user,
+                homeserver,
+                password: _,
+                token,
+            } => {
+                write!(f, "account register {protocol} {user} ")?;
+                if !homeserver.is_empty() {
+                    write!(f, "homeserver {homeserver} ")?;
+                }
+                if token.is_empty() {
+                    write!(f, "REDACTED\r\n")
+                } else {
+                    write!(f, "REDACTED REDACTED\r\n")
+                }
+            }
             Message::AccountDelete { account_id } => write!(f, "account {account_id} delete\r\n"),
+            Message::AccountLogout { account_id } => write!(f, "account {account_id} logout\r\n"),
+            Message::AccountPassword {
+                account_id,
+                password: _,
+            } => write!(f, "account {account_id} password REDACTED\r\n"),
+            Message::AccountForgetPassword { account_id } => {
+                write!(f, "account {account_id} forget-password\r\n")
+            }
+            Message::AccountSet {
+                account_id,
+                field,
+                value: _,
+            } if field == "password" => write!(f, "account {account_id} set {field} REDACTED\r\n"),
+            Message::AccountSet {
+                account_id,
+                field,
+                value,
+            } => write!(f, "account {account_id} set {field} {value}\r\n"),
+            Message::AccountEnable { account_id } => write!(f, "account {account_id} enable\r\n"),
+            Message::AccountDisable { account_id } => {
+                write!(f, "account {account_id} disable\r\n")
+            }
             Message::Buddy {
                 account_id,
                 status,
@@ -642,9 +1177,16 @@ impl std::fmt::Display for Message {
                 write!(f, "status: account {account_id} status: {status}\r\n")
             }
             Message::StatusGet { account_id } => write!(f, "account {account_id} status get\r\n"),
-            Message::StatusSet { account_id, status } => {
-                write!(f, "account {account_id} status set {status}\r\n")
-            }
+            Message::StatusSet {
+                account_id,
+                status,
+                message,
+            } if message.is_empty() => write!(f, "account {account_id} status set {status}\r\n"),
+            Message::StatusSet {
+                account_id,
+                status,
+                message,
+            } => write!(f, "account {account_id} status set {status} {message}\r\n"),
             Message::Chat {
                 account_id,
                 chat,
@@ -652,12 +1194,27 @@ impl std::fmt::Display for Message {
                 nick,
             } => write!(f, "chat: list: {account_id} {chat} {alias} {nick}\r\n"),
             Message::ChatList { account_id } => write!(f, "account {account_id} chat list\r\n"),
-            Message::ChatJoin { account_id, chat } => {
-                write!(f, "account {account_id} chat join {chat}\r\n")
-            }
-            Message::ChatLeave { account_id, chat } => {
-                write!(f, "account {account_id} chat part {chat}\r\n")
+            Message::ChatJoin {
+                account_id,
+                chat,
+                via,
+            } => {
+                write!(f, "account {account_id} chat join {chat}")?;
+                if !via.is_empty() {
+                    write!(f, " via {}", via.join(" "))?;
+                }
+                write!(f, "\r\n")
             }
+            Message::ChatLeave {
+                account_id,
+                chat,
+                forget: false,
+            } => write!(f, "account {account_id} chat part {chat}\r\n"),
+            Message::ChatLeave {
+                account_id,
+                chat,
+                forget: true,
+            } => write!(f, "account {account_id} chat part {chat} forget\r\n"),
             Message::ChatMessage {
                 account_id,
                 chat,
@@ -691,6 +1248,83 @@ impl std::fmt::Display for Message {
                 chat,
                 user,
             } => write!(f, "account {account_id} chat invite {chat} {user}\r\n"),
+            Message::ChatInfo {
+                account_id,
+                chat,
+                encryption,
+                devices,
+            } => write!(
+                f,
+                "chat: info: {account_id} {chat} {encryption} {devices}\r\n"
+            ),
+            Message::ChatInfoGet { account_id, chat } => {
+                write!(f, "account {account_id} chat info {chat}\r\n")
+            }
+            Message::ChatCreate {
+                account_id,
+                name,
+                invite,
+                encrypted,
+                public,
+            } => {
+                write!(f, "account {account_id} chat create {name}")?;
+                if !invite.is_empty() {
+                    write!(f, " invite {}", invite.join(" "))?;
+                }
+                if *encrypted {
+                    write!(f, " encrypted")?;
+                }
+                if *public {
+                    write!(f, " public")?;
+                }
+                write!(f, "\r\n")
+            }
+            Message::DirectChatCreate { account_id, user } => {
+                write!(f, "account {account_id} dm {user}\r\n")
+            }
+            Message::ChatUserTrust {
+                account_id,
+                chat,
+                user,
+                trust,
+            } => write!(f, "chat: trust: {account_id} {chat} {user} {trust}\r\n"),
+            Message::Device {
+                account_id,
+                device_id,
+                name,
+                last_seen_ip,
+                last_seen_ts,
+                status,
+            } => write!(
+                f,
+                "device: {account_id} {device_id} {name} {last_seen_ip} {last_seen_ts} {status}\r\n"
+            ),
+            Message::DeviceList { account_id } => {
+                write!(f, "account {account_id} device list\r\n")
+            }
+            Message::DeviceRename {
+                account_id,
+                device_id,
+                name,
+            } => write!(
+                f,
+                "account {account_id} device rename {device_id} {name}\r\n"
+            ),
+            Message::DeviceDelete {
+                account_id,
+                devices,
+                password,
+            } => {
+                write!(
+                    f,
+                    "account {account_id} device delete {}",
+                    devices.join(" ")
+                )?;
+                if !password.is_empty() {
+                    write!(f, " password REDACTED")?;
+                }
+                write!(f, "\r\n")
+            }
             Message::Version => write!(f, "version\r\n"),
             Message::Bye => write!(f, "bye\r\n"),
             Message::Quit => write!(f, "quit\r\n"),
@@ -723,9 +1357,76 @@ mod tests {
             Message::AccountAdd {
                 protocol: "matrix".into(),
                 user: "user".into(),
+                homeserver: "".into(),
                 password: "REDACTED".into(), // password is always "REDACTED" after to_string()
+                password_command: "".into(),
+                access_token: "".into(),
+                device_id: "".into(),
+                login_token: "".into(),
+            },
+            Message::AccountAdd {
+                protocol: "matrix".into(),
+                user: "@user:example.org".into(),
+                homeserver: "".into(),
+                password: "".into(),
+                password_command: "pass show matrix/user".into(),
+                access_token: "".into(),
+                device_id: "".into(),
+                login_token: "".into(),
+            },
+            Message::AccountAdd {
+                protocol: "matrix".into(),
+                user: "user@localhost".into(),
+                homeserver: "http://localhost:8008".into(),
+                password: "".into(),
+                password_command: "".into(),
+                access_token: "REDACTED".into(), // token is always "REDACTED" after to_string()
+                device_id: "DEVICEID".into(),
+                login_token: "".into(),
+            },
+            Message::AccountAdd {
+                protocol: "matrix".into(),
+                user: "user".into(),
+                homeserver: "https://matrix.example.org".into(),
+                password: "".into(),
+                password_command: "".into(),
+                access_token: "".into(),
+                device_id: "".into(),
+                login_token: "REDACTED".into(), // token is always "REDACTED" after to_string()
+            },
+            Message::AccountRegister {
+                protocol: "matrix".into(),
+                user: "user@localhost".into(),
+                homeserver: "http://localhost:8008".into(),
+                password: "REDACTED".into(), // password is always "REDACTED" after to_string()
+                token: "".into(),
+            },
+            Message::AccountRegister {
+                protocol: "matrix".into(),
+                user: "user@example.org".into(),
+                homeserver: "".into(),
+                password: "REDACTED".into(), // password is always "REDACTED" after to_string()
+                token: "REDACTED".into(),    // token is always "REDACTED" after to_string()
             },
             Message::AccountDelete { account_id: 1 },
+            Message::AccountLogout { account_id: 1 },
+            Message::AccountPassword {
+                account_id: 1,
+                password: "REDACTED".into(), // password is always "REDACTED" after to_string()
+            },
+            Message::AccountForgetPassword { account_id: 1 },
+            Message::AccountSet {
+                account_id: 1,
+                field: "password".into(),
+                value: "REDACTED".into(), // password is always "REDACTED" after to_string()
+            },
+            Message::AccountSet {
+                account_id: 1,
+                field: "display-name".into(),
+                value: "Test User".into(),
+            },
+            Message::AccountEnable { account_id: 1 },
+            Message::AccountDisable { account_id: 1 },
             Message::Buddy {
                 account_id: "1".into(),
                 status: "online".into(),
@@ -738,6 +1439,12 @@ mod tests {
                 name: "user".into(),
                 alias: "alias".into(),
             },
+            Message::Buddy {
+                account_id: "1".into(),
+                status: "away".into(),
+                name: "@user:example.org".into(),
+                alias: "alias".into(),
+            },
             Message::BuddyList {
                 account_id: 1,
                 status: "".into(),
@@ -781,6 +1488,12 @@ mod tests {
             Message::StatusSet {
                 account_id: "1".into(),
                 status: "online".into(),
+                message: "".into(),
+            },
+            Message::StatusSet {
+                account_id: "1".into(),
+                status: "away".into(),
+                message: "out for lunch".into(),
             },
             Message::Chat {
                 account_id: "1".into(),
@@ -794,10 +1507,22 @@ mod tests {
             Message::ChatJoin {
                 account_id: "1".into(),
                 chat: "some_chat".into(),
+                via: vec![],
+            },
+            Message::ChatJoin {
+                account_id: "1".into(),
+                chat: "#some_chat:example.org".into(),
+                via: vec!["example.org".into(), "matrix.org".into()],
+            },
+            Message::ChatLeave {
+                account_id: "1".into(),
+                chat: "some_chat".into(),
+                forget: false,
             },
             Message::ChatLeave {
                 account_id: "1".into(),
                 chat: "some_chat".into(),
+                forget: true,
             },
             Message::ChatMessage {
                 account_id: "1".into(),
@@ -839,6 +1564,71 @@ mod tests {
                 chat: "some_chat".into(),
                 user: "user".into(),
             },
+            Message::ChatInfo {
+                account_id: "1".into(),
+                chat: "some_chat".into(),
+                encryption: "encrypted".into(),
+                devices: "unverified".into(),
+            },
+            Message::ChatInfoGet {
+                account_id: "1".into(),
+                chat: "some_chat".into(),
+            },
+            Message::ChatCreate {
+                account_id: "1".into(),
+                name: "some_chat".into(),
+                invite: vec![],
+                encrypted: false,
+                public: false,
+            },
+            Message::ChatCreate {
+                account_id: "1".into(),
+                name: "some_chat".into(),
+                invite: vec!["@user1:example.org".into(), "@user2:example.org".into()],
+                encrypted: true,
+                public: true,
+            },
+            Message::DirectChatCreate {
+                account_id: "1".into(),
+                user: "@user:example.org".into(),
+            },
+            Message::ChatUserTrust {
+                account_id: "1".into(),
+                chat: "some_chat".into(),
+                user: "user".into(),
+                trust: "verified".into(),
+            },
+            Message::Device {
+                account_id: "1".into(),
+                device_id: "ABCDEFGHIJ".into(),
+                name: "nuqql-matrixd-rs".into(),
+                last_seen_ip: "127.0.0.1".into(),
+                last_seen_ts: "1700000000".into(),
+                status: "verified".into(),
+            },
+            Message::DeviceList {
+                account_id: "1".into(),
+            },
+            Message::DeviceRename {
+                account_id: "1".into(),
+                device_id: "ABCDEFGHIJ".into(),
+                name: "my device name".into(),
+            },
+            Message::DeviceDelete {
+                account_id: "1".into(),
+                devices: vec!["ABCDEFGHIJ".into()],
+                password: String::new(),
+            },
+            Message::DeviceDelete {
+                account_id: "1".into(),
+                devices: vec!["ABCDEFGHIJ".into(), "KLMNOPQRST".into()],
+                password: String::new(),
+            },
+            Message::DeviceDelete {
+                account_id: "1".into(),
+                devices: vec!["ABCDEFGHIJ".into()],
+                password: "REDACTED".into(), // password is always "REDACTED" after to_string()
+            },
             Message::Version,
             Message::Bye,
             Message::Quit,
@@ -847,4 +1637,16 @@ mod tests {
             assert_eq!(msg, msg.to_string().parse().unwrap());
         }
     }
+
+    #[test]
+    fn test_message_invalid() {
+        for msg in [
+            "account add matrix user@example.org homeserver https://example.org\r\n",
+            "account register matrix user@example.org homeserver https://example.org\r\n",
+            "account 1 device delete password\r\n",
+            "account 1 device delete ABCDEFGHIJ password\r\n",
+        ] {
+            assert!(msg.parse::<Message>().is_err(), "{msg}");
+        }
+    }
 }